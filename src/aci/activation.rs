@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::io::Write;
+use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::process::Command;
+use std::ptr;
+
+use crate::error::Error;
+
+use super::app::Port;
+
+/// The descriptor the first socket is passed as, per `sd_listen_fds(3)`.
+const LISTEN_FDS_START : RawFd = 3;
+/// What `execvp(3)` searches without a `PATH`.
+const DEFAULT_PATH : &str = "/bin:/usr/bin";
+const LISTEN_PID : &[u8] = b"LISTEN_PID=";
+/// Room for `LISTEN_PID=`, any pid and the terminating nul.
+const LISTEN_PID_LEN : usize = 32;
+
+/// A socket bound on behalf of an app, named after its port.
+pub struct ActivatedSocket {
+    fd: OwnedFd,
+    name: String
+}
+
+/// Binds the ports among `ports` that are socket-activated, on every address
+/// of the caller's network namespace.
+pub fn bind_sockets(ports: &[Port]) -> Result<Vec<ActivatedSocket>, Error> {
+    let mut sockets = Vec::new();
+    for port in ports.iter().filter(|p| p.socket_activated()) {
+        let port_error = |e| Error::Port(String::from(port.name()), e);
+        if port.port() as usize + port.count() as usize - 1 > u16::MAX as usize {
+            return Err(port_error(io::Error::new(io::ErrorKind::InvalidInput,
+                                                 "is out of range")));
+        }
+        for i in 0..port.count() {
+            let address = (Ipv4Addr::UNSPECIFIED, port.port() + i);
+            let fd = match port.protocol() {
+                "tcp" => TcpListener::bind(address).map(OwnedFd::from),
+                "udp" => UdpSocket::bind(address).map(OwnedFd::from),
+                p => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                        format!("unknown protocol {}", p)))
+            }.map_err(port_error)?;
+            sockets.push(ActivatedSocket {
+                fd,
+                name: String::from(port.name())
+            });
+        }
+    }
+    Ok(sockets)
+}
+
+fn c_string(s: OsString) -> Option<CString> {
+    CString::new(s.into_vec()).ok()
+}
+
+/// How a process gets handed its sockets. `LISTEN_PID` has to be the pid of
+/// the process itself, which is only known once forked and too late for the
+/// environment `Command` passes, so the process execs itself with one of its
+/// own making. Allocating after forking a process with threads can deadlock,
+/// so everything the forked process needs is allocated up front.
+pub struct Activation {
+    fds: Vec<RawFd>,
+    /// Room for the copies `pass_fds` makes.
+    copies: Vec<RawFd>,
+    /// Where the program may be, in the order its `PATH` gives.
+    paths: Vec<CString>,
+    /// What `argv_ptrs` and `envp_ptrs` point into.
+    #[allow(dead_code)]
+    argv: Vec<CString>,
+    #[allow(dead_code)]
+    env: Vec<CString>,
+    /// `LISTEN_PID=` and room for the pid, which `exec` fills in.
+    listen_pid: Box<[u8; LISTEN_PID_LEN]>,
+    argv_ptrs: Vec<*const libc::c_char>,
+    envp_ptrs: Vec<*const libc::c_char>
+}
+
+// The pointers only point into the strings the activation owns, whose
+// buffers stay put wherever it is moved to.
+unsafe impl Send for Activation {}
+unsafe impl Sync for Activation {}
+
+/// Where `program` may be found, as `execvp(3)` would look for it along
+/// `path`.
+fn search(program: &OsStr, path: Option<&OsString>) -> Vec<OsString> {
+    if program.as_bytes().contains(&b'/') {
+        return vec![program.to_owned()];
+    }
+    let path = path.map_or(DEFAULT_PATH.as_bytes(), |p| p.as_bytes());
+    path.split(|b| *b == b':').map(|dir| {
+        // An empty entry is the working directory.
+        let mut candidate = if dir.is_empty() {
+            OsString::from(".")
+        } else {
+            OsString::from_vec(dir.to_vec())
+        };
+        candidate.push("/");
+        candidate.push(program);
+        candidate
+    }).collect()
+}
+
+impl Activation {
+    /// Prepares handing `sockets` to what `cmd` runs, along with the
+    /// environment it would get.
+    pub fn new(cmd: &Command, sockets: &[ActivatedSocket]) -> Activation {
+        let mut vars : BTreeMap<OsString, OsString> = env::vars_os().collect();
+        for (name, value) in cmd.get_envs() {
+            match value {
+                Some(v) => vars.insert(name.to_owned(), v.to_owned()),
+                None => vars.remove(name)
+            };
+        }
+        let names : Vec<&str> = sockets.iter().map(|s| s.name.as_ref()).collect();
+        vars.remove(&OsString::from("LISTEN_PID"));
+        vars.insert(OsString::from("LISTEN_FDS"), OsString::from(format!("{}", sockets.len())));
+        vars.insert(OsString::from("LISTEN_FDNAMES"), OsString::from(names.join(":")));
+
+        // The program is looked for along the PATH it gets, not fyc's.
+        let paths = search(cmd.get_program(), vars.get(&OsString::from("PATH")));
+        let mut argv = vec![cmd.get_program().to_owned()];
+        argv.extend(cmd.get_args().map(|a| a.to_owned()));
+        let argv : Vec<CString> = argv.into_iter().filter_map(c_string).collect();
+        let env : Vec<CString> = vars.into_iter().filter_map(|(name, value)| {
+            let mut var = name;
+            var.push("=");
+            var.push(value);
+            c_string(var)
+        }).collect();
+        let mut listen_pid = Box::new([0u8; LISTEN_PID_LEN]);
+        listen_pid[..LISTEN_PID.len()].copy_from_slice(LISTEN_PID);
+
+        let mut argv_ptrs : Vec<*const libc::c_char> = argv.iter().map(|a| a.as_ptr()).collect();
+        argv_ptrs.push(ptr::null());
+        let mut envp_ptrs : Vec<*const libc::c_char> = env.iter().map(|v| v.as_ptr()).collect();
+        envp_ptrs.push(listen_pid.as_ptr() as *const libc::c_char);
+        envp_ptrs.push(ptr::null());
+        Activation {
+            fds: sockets.iter().map(|s| s.fd.as_raw_fd()).collect(),
+            copies: Vec::with_capacity(sockets.len()),
+            paths: paths.into_iter().filter_map(c_string).collect(),
+            argv,
+            env,
+            listen_pid,
+            argv_ptrs,
+            envp_ptrs
+        }
+    }
+
+    /// Moves the sockets to the descriptors from `LISTEN_FDS_START` on and
+    /// lets them survive exec, in the forked process.
+    pub unsafe fn pass_fds(&mut self) -> io::Result<()> {
+        // Copies above the range first, as a socket may sit where another
+        // one goes.
+        let above = LISTEN_FDS_START + self.fds.len() as RawFd;
+        self.copies.clear();
+        for fd in &self.fds {
+            let copy = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, above);
+            if copy < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.copies.push(copy);
+        }
+        for (i, &copy) in self.copies.iter().enumerate() {
+            if libc::dup2(copy, LISTEN_FDS_START + i as RawFd) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Execs the process, whose pid is `pid`, with `LISTEN_PID` set to it.
+    /// Only returns if that fails.
+    pub unsafe fn exec(&mut self, pid: libc::pid_t) -> io::Error {
+        // Formatting into the buffer allocates nothing.
+        let mut value = &mut self.listen_pid[LISTEN_PID.len()..];
+        if write!(value, "{}\0", pid).is_err() {
+            return io::Error::from_raw_os_error(libc::E2BIG);
+        }
+        // Like execvp, a program found but not executable is worth
+        // reporting over one not found further along.
+        let mut error = io::Error::from_raw_os_error(libc::ENOENT);
+        for path in &self.paths {
+            libc::execve(path.as_ptr(), self.argv_ptrs.as_ptr(), self.envp_ptrs.as_ptr());
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::ENOENT) | Some(libc::ENOTDIR) => {}
+                Some(libc::EACCES) => error = e,
+                _ => return e
+            }
+        }
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(program: &str, path: Option<&str>) -> Vec<OsString> {
+        search(OsStr::new(program), path.map(OsString::from).as_ref())
+    }
+
+    #[test]
+    fn search_follows_path() {
+        assert_eq!(found("app", Some("/usr/bin::/bin")),
+                   vec!["/usr/bin/app", "./app", "/bin/app"]);
+        assert_eq!(found("app", None), vec!["/bin/app", "/usr/bin/app"]);
+        assert_eq!(found("./app", Some("/usr/bin")), vec!["./app"]);
+        assert_eq!(found("/opt/app", Some("/usr/bin")), vec!["/opt/app"]);
+    }
+
+    #[test]
+    fn activation_searches_the_app_path() {
+        let mut cmd = Command::new("app");
+        cmd.env("PATH", "/app/bin");
+        let activation = Activation::new(&cmd, &[]);
+        assert_eq!(activation.paths, vec![CString::new("/app/bin/app").unwrap()]);
+        assert!(activation.env.contains(&CString::new("LISTEN_FDS=0").unwrap()));
+    }
+    #[test]
+    fn exec_environment_is_ready_before_forking() {
+        let mut cmd = Command::new("/nowhere/app");
+        cmd.arg("--flag");
+        let mut activation = Activation::new(&cmd, &[]);
+        assert_eq!(activation.argv_ptrs.len(), 3);
+        assert!(activation.argv_ptrs[2].is_null());
+        assert!(activation.envp_ptrs.last().unwrap().is_null());
+        let error = unsafe { activation.exec(4242) };
+        assert_eq!(error.raw_os_error(), Some(libc::ENOENT));
+        let listen_pid = activation.envp_ptrs[activation.envp_ptrs.len() - 2];
+        assert_eq!(unsafe { std::ffi::CStr::from_ptr(listen_pid) }, c"LISTEN_PID=4242");
+    }
+}
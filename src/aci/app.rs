@@ -1,25 +1,32 @@
-use libc::{chroot, mount, MS_BIND, MS_RDONLY, MS_NODEV, MS_NOEXEC, MS_NOSUID};
+use libc::{chroot, mount, MS_BIND};
 use libc;
 
-use crate::metadata;
+use crate::error::Error;
+
+use serde_json;
 
-use std::collections::HashSet;
 use std::env::set_current_dir;
 use std::ffi::CString;
-use std::fs::create_dir;
+use std::fs::write;
 use std::io;
 use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::process::Command;
 use std::ptr;
-use uuid::Uuid;
 use crate::util::vec_or_empty;
 use crate::util::NameValue;
 
-use super::MountPoint;
+use super::{Capabilities, MountPoint, Seccomp, VolumeMount};
+use super::activation::{ActivatedSocket, Activation};
+use super::capabilities::raise_permitted;
+use super::identity::{resolve_group, resolve_user};
+use super::resolve::Kind;
+use super::system::{mount_in_root, mount_system_volumes, remount_read_only};
 
-const ACE_PATH: &'static str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
-const FYC: &'static str = "fyc";
+const ACE_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+const FYC: &str = "fyc";
+const NO_NEW_PRIVILEGES: &str = "os/linux/no-new-privileges";
+const OOM_SCORE_ADJ: &str = "os/linux/oom-score-adj";
 
 #[derive(Clone, Serialize, Deserialize)]
 struct EventHandler {
@@ -34,82 +41,160 @@ pub struct Port {
     protocol: String,
     port: u16,
     count: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
     socketActivated: Option<bool>
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Isolator {
+    pub name: String,
+    pub value: serde_json::Value
+}
+
+impl Port {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// How many ports the range starting at `port` holds.
+    pub fn count(&self) -> u16 {
+        self.count.max(1)
+    }
+
+    /// Whether the app expects the port bound and handed to it.
+    pub fn socket_activated(&self) -> bool {
+        self.socketActivated.unwrap_or(false)
+    }
+}
+
+/// How an app's process is confined to its rootfs.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RootSwitch {
+    Chroot,
+    /// Makes the rootfs the root of a mount namespace of the app's own and
+    /// detaches the old one, leaving nothing of the host reachable. Falls
+    /// back to `Chroot` where mount namespaces are unavailable.
+    PivotRoot
+}
+
+/// How the processes of an app are started.
+pub struct ExecOptions {
+    /// Where the app finds the pod's metadata, its `AC_METADATA_URL`.
+    pub metadata_url: String,
+    pub root_switch: RootSwitch,
+    pub uid: u32,
+    pub gid: u32,
+    /// The `cgroup.procs` file of the cgroup the processes join.
+    pub cgroup_procs: Option<String>,
+    pub capabilities: Capabilities,
+    pub no_new_privileges: bool,
+    pub oom_score_adj: Option<i32>,
+    pub seccomp: Seccomp
 }
 
 #[allow(non_snake_case)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct App {
+    #[serde(skip_serializing_if = "Option::is_none")]
     exec: Option<Vec<String>>,
     user: String,
     group: String,
-    supplementaryGIDs: Option<Vec<usize>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    supplementaryGIDs: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     eventHandlers: Option<Vec<EventHandler>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     workingDirectory: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     environment: Option<Vec<NameValue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     isolators: Option<Vec<Isolator>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     mountPoints: Option<Vec<MountPoint>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     ports: Option<Vec<Port>>
 }
 
-fn mount_system_volumes(app_path: &str, mount_points: &mut Vec<CString>) {
-    let system_volumes = vec!["proc", "sys", "dev"];
-
-    for system_volume in system_volumes {
-        let mut mount_dir = String::from(app_path);
-        mount_dir.push_str(system_volume);
-        mount_dir.push('/');
-
-        let mount_dst = CString::new(mount_dir.clone()).unwrap();
-
-        let mut mount_src_str = String::from("/");
-        mount_src_str.push_str(system_volume);
-        let mount_src = CString::new("/proc").unwrap();
-
-        let mount_flags = MS_BIND | MS_NODEV | MS_NOSUID | MS_NOEXEC;
-
-        if let Err(e) = create_dir(Path::new(&mount_dir)) {
-            println!("Error creating directory for volume! Oh no: {}", e);
-            return;
+/// Whether the `no-new-privileges` isolator among `isolators` asks for
+/// processes that can't gain privileges through exec, as of setuid binaries.
+pub fn no_new_privileges(isolators: &[Isolator]) -> Result<bool, Error> {
+    let mut set = false;
+    for isolator in isolators {
+        if isolator.name == NO_NEW_PRIVILEGES {
+            set = serde_json::from_value(isolator.value.clone())
+                .map_err(|e| Error::Isolator(isolator.name.clone(), e.into()))?;
         }
+    }
+    Ok(set)
+}
 
-        unsafe {
-            let e = mount(mount_src.as_ptr(), mount_dst.as_ptr(),
-                          ptr::null(), mount_flags, ptr::null());
-            if e != 0 {
-                println!("Oh no, could not mount a volume: {:?}",
-                         *libc::__errno_location());
+/// The `oom_score_adj` the `oom-score-adj` isolator among `isolators` gives
+/// the app's processes, if any.
+pub fn oom_score_adj(isolators: &[Isolator]) -> Result<Option<i32>, Error> {
+    let mut adj = None;
+    for isolator in isolators {
+        if isolator.name == OOM_SCORE_ADJ {
+            let value : i32 = serde_json::from_value(isolator.value.clone())
+                .map_err(|e| Error::Isolator(isolator.name.clone(), e.into()))?;
+            if !(-1000..=1000).contains(&value) {
+                return Err(Error::Isolator(isolator.name.clone(), io::Error::new(
+                    io::ErrorKind::InvalidData, "is not between -1000 and 1000")));
             }
+            adj = Some(value);
         }
-        mount_points.push(mount_dst);
     }
+    Ok(adj)
+}
+
+/// Switches the root to the current directory, which must be a mount point,
+/// in a new mount namespace. Returns `Ok(false)` when no mount namespace can
+/// be had.
+unsafe fn pivot_root() -> io::Result<bool> {
+    if libc::unshare(libc::CLONE_NEWNS) != 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EPERM) | Some(libc::EINVAL) | Some(libc::ENOSYS) => Ok(false),
+            _ => Err(e)
+        };
+    }
+
+    let root = c"/".as_ptr();
+    let dot = c".".as_ptr();
+    // pivot_root refuses to move mounts that propagate elsewhere.
+    if mount(ptr::null(), root, ptr::null(), libc::MS_REC | libc::MS_PRIVATE,
+             ptr::null()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // With both roots on ".", the old one ends up stacked on top of the new
+    // one and can be detached right away.
+    if libc::syscall(libc::SYS_pivot_root, dot, dot) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if libc::umount2(dot, libc::MNT_DETACH) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if libc::chdir(root) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(true)
 }
 
 impl App {
-    fn prep_cmd(&self, exec: &Vec<String>, dir: &str,
-                app_name: &str, pod_uuid: Uuid) -> Command {
+    fn prep_cmd(&self, exec: &[String], dir: &str, app_name: &str,
+                options: &ExecOptions, sockets: &[ActivatedSocket]) -> Command {
         let mut cmd = Command::new(&exec[0]);
         cmd.args(&exec[1..]);
-        if let Ok(userid) = self.user.parse::<u32>() {
-            cmd.uid(userid);
-        }
-
-        if let Ok(groupid) = self.group.parse::<u32>() {
-            cmd.gid(groupid);
-        }
-
-        let mut metadata_url = String::from("http://");
-        metadata_url.push_str(metadata::HOST_PORT);
-        metadata_url.push('/');
-        metadata_url.push_str(&pod_uuid.hyphenated().to_string());
-
         cmd.env("PATH", ACE_PATH);
         cmd.env("AC_APP_NAME", app_name);
-        cmd.env("AC_METADATA_URL", metadata_url);
+        cmd.env("AC_METADATA_URL", &options.metadata_url);
         cmd.env("container", FYC);
         if let Some(ref env_vars) = self.environment {
             for ekv in env_vars {
@@ -118,131 +203,172 @@ impl App {
         }
 
         let closed_dir = String::from(dir);
-        let work_dir = match self.workingDirectory {
-            None => None,
-            Some(ref wdir) => Some(wdir.clone())
+        let root_switch = options.root_switch;
+        let uid = options.uid;
+        let gid = options.gid;
+        let groups = vec_or_empty(self.supplementaryGIDs.as_ref());
+        let cgroup_procs = options.cgroup_procs.clone();
+        let capabilities = options.capabilities;
+        let no_new_privileges = options.no_new_privileges;
+        // Nothing is allocated once forked: allocating after forking a
+        // process with threads can deadlock.
+        let oom_score_adj = options.oom_score_adj.map(|adj| adj.to_string());
+        let c_dir = CString::new(dir).unwrap();
+        let seccomp = options.seccomp.clone();
+        let work_dir = self.workingDirectory.clone();
+        let mut activation = if sockets.is_empty() {
+            None
+        } else {
+            Some(Activation::new(&cmd, sockets))
         };
 
         unsafe {
             cmd.pre_exec(move || {
-                match set_current_dir(&closed_dir) {
-                    Err(e) => {
-                        println!("chdir failed: {}", e);
-                        return Err(e);
-                    }
-                    _ => {}
+                if let Some(ref adj) = oom_score_adj {
+                    write("/proc/self/oom_score_adj", adj)?;
                 }
 
-                let c_dir = CString::new(closed_dir.clone()).unwrap();
+                if let Some(ref procs) = cgroup_procs {
+                    write(procs, "0")?;
+                }
 
-                let e = chroot(c_dir.as_ptr());
-                if e != 0 {
-                    println!("Chroot unsuccessful!");
+                if let Some(ref mut a) = activation {
+                    a.pass_fds()?;
+                }
+
+                set_current_dir(&closed_dir)?;
+
+                let pivoted = match root_switch {
+                    RootSwitch::PivotRoot => pivot_root()?,
+                    RootSwitch::Chroot => false
+                };
+
+                if !pivoted && chroot(c_dir.as_ptr()) != 0 {
                     return Err(io::Error::last_os_error());
                 }
 
-                match work_dir {
-                    None => {},
-                    Some(ref wdir) => {
-                        match set_current_dir(wdir) {
-                            Err(e) => {
-                                println!("chdir failed: {}", e);
-                                return Err(e);
-                            }
-                            _ => {}
-                        }
+                if let Some(ref wdir) = work_dir {
+                    set_current_dir(wdir)?;
+                }
+
+                // Installing the filter takes no-new-privileges or
+                // CAP_SYS_ADMIN, which the app may not keep, so it comes
+                // after everything fyc does but dropping CAP_SYS_ADMIN,
+                // which the filter lets through.
+                if no_new_privileges {
+                    if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                        return Err(io::Error::last_os_error());
                     }
+                } else if libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // The process is about to be the app's, whose filter may
+                // refuse it that.
+                let pid = libc::getpid();
+
+                // Switching users needs CAP_SETUID and CAP_SETGID, which the
+                // app may not keep, and clears the effective capabilities.
+                capabilities.drop_bounding()?;
+                // Whatever groups fyc is in must not leak into the app.
+                if libc::setgroups(groups.len(), groups.as_ptr()) != 0 ||
+                    libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if no_new_privileges {
+                    capabilities.apply()?;
+                    seccomp.apply()?;
+                } else {
+                    raise_permitted()?;
+                    seccomp.apply()?;
+                    capabilities.apply()?;
                 }
 
-                Ok(())
+                match activation {
+                    Some(ref mut a) => Err(a.exec(pid)),
+                    None => Ok(())
+                }
             });
         }
         cmd
     }
 
-    fn mount_points_or_empty(&self) -> Vec<MountPoint> {
-        vec_or_empty(self.mountPoints.as_ref())
+    /// Resolves `user` and `group` in the rootfs at `rootfs`.
+    pub fn identity(&self, rootfs: &str) -> io::Result<(u32, u32)> {
+        Ok((resolve_user(rootfs, &self.user)?, resolve_group(rootfs, &self.group)?))
     }
 
-    pub fn mount_volumes(&self, vol_path: &str, app_path: &str,
-                         volumes: &mut HashSet<String>,
-                         mount_points: &mut Vec<CString>) {
-        mount_system_volumes(app_path, mount_points);
-
-        for mount_point in self.mount_points_or_empty() {
-            let mut mount_src_str = String::from(vol_path);
-            mount_src_str.push_str(&mount_point.name);
+    pub fn isolators_or_empty(&self) -> Vec<Isolator> {
+        vec_or_empty(self.isolators.as_ref())
+    }
 
-            if !volumes.contains(&mount_point.name) {
-                match create_dir(Path::new(&mount_src_str)) {
-                    Err(_) => {
-                        println!("Error creating directory for volume! Oh no!");
-                        return;
-                    }
-                    _ => {}
-                }
-                volumes.insert(mount_point.name.clone());
-            }
+    pub fn ports_or_empty(&self) -> Vec<Port> {
+        vec_or_empty(self.ports.as_ref())
+    }
 
-            let mount_src = CString::new(mount_src_str).unwrap();
+    pub fn mount_points_or_empty(&self) -> Vec<MountPoint> {
+        vec_or_empty(self.mountPoints.as_ref())
+    }
 
-            let mut mount_dst_str = String::from(app_path);
-            mount_dst_str.push_str(&mount_point.path);
-            match create_dir(Path::new(&mount_dst_str)) {
-                Err(_) => {
-                    println!("Error creating directory for volume! Oh no!");
-                    return;
-                }
-                _ => {}
-            }
-            let mount_dst = CString::new(mount_dst_str).unwrap();
+    pub fn mount_volumes(&self, app_path: &str, mounts: &[VolumeMount],
+                         proc_dir: Option<&str>,
+                         mount_points: &mut Vec<CString>) -> Result<(), Error> {
+        mount_system_volumes(app_path, proc_dir, mounts, mount_points)?;
 
-            let mount_flags = if mount_point.read_only() {
-                MS_BIND & MS_RDONLY
+        for volume_mount in mounts {
+            let kind = if Path::new(&volume_mount.source).is_dir() {
+                Kind::Dir
             } else {
-                MS_BIND
+                Kind::File
             };
-
-            unsafe {
-                let e = mount(mount_src.as_ptr(), mount_dst.as_ptr(),
-                              ptr::null(), mount_flags, ptr::null());
-                if e != 0 {
-                    println!("Oh no, could not mount a volume: {:?}",
-                             *libc::__errno_location());
+            let target = mount_in_root(app_path, &volume_mount.path, kind, mount_points, |t| {
+                let source = CString::new(volume_mount.source.clone()).unwrap();
+                let target = CString::new(t).unwrap();
+                if unsafe {
+                    mount(source.as_ptr(), target.as_ptr(), ptr::null(), MS_BIND, ptr::null())
+                } != 0 {
+                    return Err(io::Error::last_os_error());
                 }
+                Ok(())
+            })?;
+            // A bind mount takes the flags of what it binds, so it can
+            // only be made read-only once it's there.
+            if volume_mount.read_only {
+                remount_read_only(&target)?;
             }
-            mount_points.push(mount_dst);
         }
+        Ok(())
     }
 
     fn find_event_handle(&self, ehs: &Vec<EventHandler>, dir: &str,
-                         app_name: &str, pod_uuid: Uuid,
+                         app_name: &str, options: &ExecOptions,
                          event_name: &str) -> Option<Command> {
         for eh in ehs {
             if eh.name == event_name {
-                return Some(self.prep_cmd(&eh.exec, dir, app_name, pod_uuid));
+                return Some(self.prep_cmd(&eh.exec, dir, app_name, options, &[]));
             }
         }
-        return None;
+        None
     }
 
-    pub fn exec_app(&self, dir: &str, app_name: &str,
-                pod_uuid: Uuid) -> (Option<Command>, Option<Command>,
-                                    Option<Command>) {
+    /// Prepares the app, handed `sockets`, and its pre-start and post-stop
+    /// event handlers.
+    pub fn exec_app(&self, dir: &str, app_name: &str, options: &ExecOptions,
+                    sockets: &[ActivatedSocket]) -> (Option<Command>, Option<Command>,
+                                                     Option<Command>) {
         let app_child = if let Some(ref exec) = self.exec {
-            self.prep_cmd(exec, dir, app_name, pod_uuid)
+            self.prep_cmd(exec, dir, app_name, options, sockets)
         } else {
             return (None, None, None);
         };
 
         let pre_start = if let Some(ref ehs) = self.eventHandlers {
-            self.find_event_handle(ehs, dir, app_name, pod_uuid, "pre-start")
+            self.find_event_handle(ehs, dir, app_name, options, "pre-start")
         } else {
             None
         };
 
         let post_stop = if let Some(ref ehs) = self.eventHandlers {
-            self.find_event_handle(ehs, dir, app_name, pod_uuid, "post-stop")
+            self.find_event_handle(ehs, dir, app_name, options, "post-stop")
         } else {
             None
         };
@@ -0,0 +1,178 @@
+use std::io;
+
+use crate::error::Error;
+
+use super::Isolator;
+
+const RETAIN_SET : &str = "os/linux/capabilities-retain-set";
+const REMOVE_SET : &str = "os/linux/capabilities-remove-set";
+const CAPABILITY_VERSION_3 : u32 = 0x20080522;
+
+/// Indexed by capability number.
+const NAMES : [&str; 41] = [
+    "CAP_CHOWN", "CAP_DAC_OVERRIDE", "CAP_DAC_READ_SEARCH", "CAP_FOWNER",
+    "CAP_FSETID", "CAP_KILL", "CAP_SETGID", "CAP_SETUID", "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE", "CAP_NET_BIND_SERVICE", "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN", "CAP_NET_RAW", "CAP_IPC_LOCK", "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE", "CAP_SYS_RAWIO", "CAP_SYS_CHROOT", "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT", "CAP_SYS_ADMIN", "CAP_SYS_BOOT", "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE", "CAP_SYS_TIME", "CAP_SYS_TTY_CONFIG", "CAP_MKNOD",
+    "CAP_LEASE", "CAP_AUDIT_WRITE", "CAP_AUDIT_CONTROL", "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE", "CAP_MAC_ADMIN", "CAP_SYSLOG", "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND", "CAP_AUDIT_READ", "CAP_PERFMON", "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE"
+];
+
+/// What apps get when their image has no capability isolator, per the spec.
+const DEFAULT_SET : [&str; 14] = [
+    "CAP_AUDIT_WRITE", "CAP_CHOWN", "CAP_DAC_OVERRIDE", "CAP_FSETID",
+    "CAP_FOWNER", "CAP_KILL", "CAP_MKNOD", "CAP_NET_RAW",
+    "CAP_NET_BIND_SERVICE", "CAP_SETUID", "CAP_SETGID", "CAP_SETPCAP",
+    "CAP_SETFCAP", "CAP_SYS_CHROOT"
+];
+
+#[derive(Deserialize)]
+struct CapabilitySet {
+    set: Vec<String>
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32
+}
+
+/// The capabilities an app's processes keep, as a bit per capability.
+#[derive(Clone, Copy)]
+pub struct Capabilities {
+    set: u64
+}
+
+/// Returns the bits of the named capabilities, or the first name that isn't
+/// one.
+fn mask<S: AsRef<str>>(names: &[S]) -> Result<u64, String> {
+    let mut set = 0;
+    for name in names {
+        match NAMES.iter().position(|n| *n == name.as_ref()) {
+            Some(cap) => set |= 1 << cap,
+            None => return Err(String::from(name.as_ref()))
+        }
+    }
+    Ok(set)
+}
+
+impl Capabilities {
+    /// Works out the capabilities left by the `capabilities-retain-set` or
+    /// `capabilities-remove-set` isolator among `isolators`, which can't
+    /// both be given.
+    pub fn from_isolators(isolators: &[Isolator]) -> Result<Capabilities, Error> {
+        let default_set = mask(&DEFAULT_SET).unwrap();
+        let mut capabilities = None;
+        for isolator in isolators {
+            if isolator.name != RETAIN_SET && isolator.name != REMOVE_SET {
+                continue;
+            }
+            if capabilities.is_some() {
+                return Err(Error::Isolator(isolator.name.clone(), io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "only one capability isolator may be given")));
+            }
+            let set : CapabilitySet = serde_json::from_value(isolator.value.clone())
+                .map_err(|e| Error::Isolator(isolator.name.clone(), e.into()))?;
+            let set = mask(&set.set).map_err(|name| Error::Isolator(
+                isolator.name.clone(),
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("unknown capability {}", name))))?;
+            capabilities = Some(if isolator.name == RETAIN_SET {
+                set
+            } else {
+                default_set & !set
+            });
+        }
+        Ok(Capabilities {
+            set: capabilities.unwrap_or(default_set)
+        })
+    }
+
+    /// Drops every other capability from the bounding and ambient sets of the
+    /// calling process, which takes CAP_SETPCAP. Capabilities in use are
+    /// left alone until `apply`.
+    pub unsafe fn drop_bounding(self) -> io::Result<()> {
+        for cap in 0..64 {
+            if self.set & (1 << cap) != 0 {
+                continue;
+            }
+            if libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) != 0 {
+                let e = io::Error::last_os_error();
+                // Past the last capability the kernel knows of.
+                if e.raw_os_error() == Some(libc::EINVAL) {
+                    break;
+                }
+                return Err(e);
+            }
+        }
+
+        if libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong,
+                       0, 0, 0) != 0 {
+            let e = io::Error::last_os_error();
+            // Kernels without ambient capabilities have none to clear.
+            if e.raw_os_error() != Some(libc::EINVAL) {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops every other capability from the inheritable, permitted and
+    /// effective sets of the calling process. Nothing needing one of the
+    /// dropped capabilities can be done afterwards, so this comes last
+    /// before exec.
+    pub unsafe fn apply(self) -> io::Result<()> {
+        let (mut header, mut data) = get()?;
+        for (i, d) in data.iter_mut().enumerate() {
+            let keep = (self.set >> (32 * i)) as u32;
+            d.permitted &= keep;
+            d.effective = d.permitted;
+            d.inheritable = d.permitted;
+        }
+        set(&mut header, &data)
+    }
+}
+
+unsafe fn get() -> io::Result<(CapHeader, [CapData; 2])> {
+    let mut header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0
+    };
+    let mut data = [CapData::default(); 2];
+    if libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((header, data))
+}
+
+unsafe fn set(header: &mut CapHeader, data: &[CapData; 2]) -> io::Result<()> {
+    if libc::syscall(libc::SYS_capset, header as *mut CapHeader, data.as_ptr()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes every permitted capability of the calling process effective again,
+/// as they are no longer after switching to another user with
+/// `PR_SET_KEEPCAPS`.
+pub unsafe fn raise_permitted() -> io::Result<()> {
+    let (mut header, mut data) = get()?;
+    for d in data.iter_mut() {
+        d.effective = d.permitted;
+    }
+    set(&mut header, &data)
+}
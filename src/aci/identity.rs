@@ -0,0 +1,133 @@
+use std::fs::{File, Metadata};
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::MetadataExt;
+
+use super::resolve::open_existing_in_root;
+
+const PASSWD : &str = "etc/passwd";
+const GROUP : &str = "etc/group";
+
+fn not_found(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, what)
+}
+
+/// Looks `name` up in a passwd or group file, whose third field is the ID.
+/// The file is looked for inside the rootfs, symlinks and all.
+fn find_entry(rootfs: &str, db: &str, name: &str) -> io::Result<u32> {
+    let f = open_existing_in_root(rootfs, db, libc::O_RDONLY)
+        .map(File::from)
+        .map_err(|e| io::Error::new(e.kind(), format!("/{}: {}", db, e)))?;
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        let fields : Vec<&str> = line.split(':').collect();
+        if fields.len() > 2 && fields[0] == name {
+            return fields[2].parse().map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidData, format!("bad ID for {} in /{}", name, db)));
+        }
+    }
+    Err(not_found(format!("no {} in /{}", name, db)))
+}
+
+/// The metadata of the file at the absolute `path` inside the rootfs,
+/// which neither `..` nor symlinks lead out of.
+fn owner_of(rootfs: &str, path: &str) -> io::Result<Metadata> {
+    open_existing_in_root(rootfs, path, libc::O_PATH)
+        .and_then(|fd| File::from(fd).metadata())
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+/// Finds the ID `id` stands for in the rootfs at `rootfs`: a number, an
+/// entry of `db`, or the absolute path of a file it owns.
+fn resolve(rootfs: &str, id: &str, db: &str,
+           owner: fn(&Metadata) -> u32) -> io::Result<u32> {
+    if let Ok(n) = id.parse::<u32>() {
+        return Ok(n);
+    }
+    if id.starts_with('/') {
+        return owner_of(rootfs, id).map(|m| owner(&m));
+    }
+    if id.is_empty() {
+        return Err(not_found(String::from("no user or group given")));
+    }
+    find_entry(rootfs, db, id)
+}
+
+/// The UID of the `user` of an app whose rootfs is at `rootfs`.
+pub fn resolve_user(rootfs: &str, user: &str) -> io::Result<u32> {
+    resolve(rootfs, user, PASSWD, |m| m.uid())
+}
+
+/// The GID of the `group` of an app whose rootfs is at `rootfs`.
+pub fn resolve_group(rootfs: &str, group: &str) -> io::Result<u32> {
+    resolve(rootfs, group, GROUP, |m| m.gid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::os::unix::fs::{chown, symlink};
+    use std::path::Path;
+
+    use crate::util::test_dir;
+
+    /// A rootfs whose `/etc/group` is a symlink that only makes sense
+    /// inside it.
+    fn rootfs(name: &str) -> String {
+        let dir = test_dir(&format!("identity-{}", name));
+        create_dir_all(dir.join("etc")).unwrap();
+        write(dir.join("etc/passwd"), "root:x:0:0::/root:/bin/sh\napp:x:1000:1000::/:/bin/sh\n")
+            .unwrap();
+        write(dir.join("etc/group.real"), "root:x:0:\napp:x:2000:\n").unwrap();
+        symlink("/../../../etc/group.real", dir.join("etc/group")).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn names_and_numbers() {
+        let root = rootfs("names");
+        assert_eq!(resolve_user(&root, "42").unwrap(), 42);
+        assert_eq!(resolve_user(&root, "app").unwrap(), 1000);
+        assert_eq!(resolve_group(&root, "0").unwrap(), 0);
+        assert_eq!(resolve_group(&root, "app").unwrap(), 2000);
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn missing_entries_are_errors() {
+        let root = rootfs("missing");
+        assert_eq!(resolve_user(&root, "nobody").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(resolve_group(&root, "").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(resolve_user(&root, "/nowhere").unwrap_err().kind(),
+                   io::ErrorKind::NotFound);
+        assert!(!Path::new(&root).join("nowhere").exists());
+        remove_dir_all(&root).unwrap();
+        assert!(resolve_user(&root, "app").is_err());
+    }
+
+    #[test]
+    fn paths_stay_in_the_rootfs() {
+        let root = rootfs("paths");
+        let owned = Path::new(&root).join("owned");
+        write(&owned, "").unwrap();
+        let (uid, gid) = if unsafe { libc::geteuid() } == 0 {
+            chown(&owned, Some(1234), Some(5678)).unwrap();
+            (1234, 5678)
+        } else {
+            let md = owned.metadata().unwrap();
+            (md.uid(), md.gid())
+        };
+        symlink("/owned", Path::new(&root).join("link")).unwrap();
+        assert_eq!(resolve_user(&root, "/owned").unwrap(), uid);
+        assert_eq!(resolve_group(&root, "/owned").unwrap(), gid);
+        assert_eq!(resolve_user(&root, "/../../../../owned").unwrap(), uid);
+        assert_eq!(resolve_user(&root, "/link").unwrap(), uid);
+        // The host's /etc/passwd, owned by root, is out of reach.
+        symlink("/etc/passwd", Path::new(&root).join("host")).unwrap();
+        chown(Path::new(&root).join("etc/passwd"), Some(uid), Some(gid)).unwrap();
+        assert_eq!(resolve_user(&root, "/host").unwrap(), uid);
+        remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,80 +1,321 @@
-use libc;
-
-use std::collections::HashSet;
-use std::ffi::CString;
-use std::io::Error;
+use std::ffi::{CString, OsStr};
+use std::fs::create_dir;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::process::Command;
+use std::ptr;
 
-use serde_json;
-use uuid::Uuid;
+use crate::error::Error;
+use crate::util::vec_or_empty;
 use crate::util::NameValue;
 
+mod activation;
 pub mod app;
+mod capabilities;
+mod identity;
 mod mountpoint;
+mod resolve;
+mod seccomp;
+mod syscalls;
+mod system;
 
 use self::app::App;
-pub use self::mountpoint::MountPoint;
-pub use self::app::Isolator;
+pub use self::activation::{bind_sockets, ActivatedSocket};
+pub use self::capabilities::Capabilities;
+pub use self::mountpoint::{MountPoint, VolumeMount};
+pub use self::seccomp::Seccomp;
+pub use self::app::{no_new_privileges, oom_score_adj, ExecOptions, Isolator, RootSwitch};
 
 #[allow(non_snake_case)]
 #[derive(Clone, Serialize, Deserialize)]
-struct Dependency {
+pub struct Dependency {
     imageName: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     imageID: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     labels: Option<Vec<NameValue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     size: Option<usize>
 }
 
+impl Dependency {
+    pub fn get_image_name(&self) -> String {
+        self.imageName.clone()
+    }
+
+    pub fn get_image_id(&self) -> Option<String> {
+        self.imageID.clone()
+    }
+
+    pub fn labels_or_empty(&self) -> Vec<NameValue> {
+        vec_or_empty(self.labels.as_ref())
+    }
+}
+
 #[allow(non_snake_case)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct AciJson {
     acKind: String,
     acVersion: String,
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     labels: Option<Vec<NameValue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     app: Option<App>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     dependencies: Option<Vec<Dependency>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pathWhitelist: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     annotations: Option<Vec<NameValue>>
 }
 
-pub struct ACI {
+impl AciJson {
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn labels_or_empty(&self) -> Vec<NameValue> {
+        vec_or_empty(self.labels.as_ref())
+    }
+
+    pub fn dependencies_or_empty(&self) -> Vec<Dependency> {
+        vec_or_empty(self.dependencies.as_ref())
+    }
+
+    /// An empty whitelist means the same as none at all: keep everything.
+    pub fn path_whitelist(&self) -> Option<Vec<String>> {
+        match self.pathWhitelist {
+            Some(ref w) if !w.is_empty() => Some(w.clone()),
+            _ => None
+        }
+    }
+
+    pub fn ports(&self) -> Vec<app::Port> {
+        match self.app {
+            None => Vec::new(),
+            Some(ref a) => a.ports_or_empty()
+        }
+    }
+
+    /// The app name used when running an image outside of a pod manifest.
+    pub fn app_name(&self) -> String {
+        String::from(self.name.split('/').next_back().unwrap())
+    }
+}
+
+pub struct Aci {
     json: AciJson,
     mount_points: Vec<CString>
 }
 
-impl ACI {
-    pub fn new(manifest_str: &str) -> Result<ACI, Error> {
-        let json : AciJson = serde_json::from_str(&manifest_str)?;
-
-        Ok(ACI {
-            json: json,
+impl Aci {
+    pub fn new(json: AciJson) -> Aci {
+        Aci {
+            json,
             mount_points: Vec::new()
-        })
+        }
+    }
+
+    /// Gives the app a private, writable view of an image's rootfs layers
+    /// (topmost first) that are shared through the store, by mounting an
+    /// overlay at `app_dir/rootfs`. Returns the path of the mounted rootfs.
+    pub fn mount_rootfs(&mut self, layers: &[String],
+                        app_dir: &str) -> Result<String, Error> {
+        let mut upper_dir = String::from(app_dir);
+        upper_dir.push_str("upper");
+        let mut work_dir = String::from(app_dir);
+        work_dir.push_str("work");
+        let mut rootfs_dir = String::from(app_dir);
+        rootfs_dir.push_str("rootfs/");
+        create_dir(&upper_dir)?;
+        create_dir(&work_dir)?;
+        create_dir(&rootfs_dir)?;
+
+        let options = CString::new(format!("lowerdir={},upperdir={},workdir={}",
+                                           layers.join(":"), upper_dir, work_dir)).unwrap();
+        let fs_type = CString::new("overlay").unwrap();
+        let rootfs = CString::new(rootfs_dir.clone()).unwrap();
+        unsafe {
+            let e = libc::mount(fs_type.as_ptr(), rootfs.as_ptr(), fs_type.as_ptr(),
+                                0, options.as_ptr() as *const libc::c_void);
+            if e != 0 {
+                return Err(Error::Mount(rootfs_dir, io::Error::last_os_error()));
+            }
+        }
+        self.mount_points.push(rootfs);
+        Ok(rootfs_dir)
+    }
+
+    pub fn mount_points(&self) -> Vec<MountPoint> {
+        match self.json.app {
+            None => Vec::new(),
+            Some(ref a) => a.mount_points_or_empty()
+        }
     }
 
-    pub fn mount_volumes(&mut self, vol_path: &str, app_path: &str, volumes: &mut HashSet<String>) {
+    /// Mounts the app's volumes, system directories and the pod's files from
+    /// `etc_dir`, taking `/proc` from `proc_dir` if given.
+    pub fn mount_volumes(&mut self, app_path: &str, mounts: &[VolumeMount],
+                         proc_dir: Option<&str>, etc_dir: &str,
+                         read_only_rootfs: bool) -> Result<(), Error> {
         if let Some(ref a) = self.json.app {
-            a.mount_volumes(vol_path, app_path, volumes, &mut self.mount_points)
+            a.mount_volumes(app_path, mounts, proc_dir, &mut self.mount_points)?;
         }
+        system::mount_etc_files(app_path, etc_dir, mounts, &mut self.mount_points)?;
+        if read_only_rootfs {
+            self.mount_rootfs_read_only(app_path)?;
+        }
+        Ok(())
     }
 
-    pub fn unmount_volumes(self) {
-        for mount_point in self.mount_points {
-            unsafe {
-                let e = libc::umount(mount_point.as_ptr());
-                if e != 0 {
-                    println!("Oh no, could not unmount: {:?}", *libc::__errno_location());
-                }
+    fn mount_rootfs_read_only(&mut self, app_path: &str) -> Result<(), Error> {
+        let rootfs = CString::new(app_path).unwrap();
+        unsafe {
+            // The rootfs is already the overlay's mount point, but binding
+            // it onto itself, volumes and all, gives a top mount of its own
+            // to make read-only. The volumes come along as mounts of their
+            // own and keep their flags.
+            let e = libc::mount(rootfs.as_ptr(), rootfs.as_ptr(), ptr::null(),
+                                libc::MS_BIND | libc::MS_REC, ptr::null());
+            if e != 0 {
+                return Err(Error::Mount(String::from(app_path),
+                                        io::Error::last_os_error()));
+            }
+            self.mount_points.push(rootfs.clone());
+            let e = libc::mount(ptr::null(), rootfs.as_ptr(), ptr::null(),
+                                libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY,
+                                ptr::null());
+            if e != 0 {
+                return Err(Error::Mount(String::from(app_path),
+                                        io::Error::last_os_error()));
             }
         }
+        Ok(())
+    }
+
+    /// Unmounts everything that was mounted for this app, newest first. All
+    /// of them are attempted even if one fails; the first failure is
+    /// returned.
+    pub fn unmount_volumes(self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for (i, mount_point) in self.mount_points.iter().enumerate().rev() {
+            // Detaching a mount takes those below it along, which spares
+            // looking up paths the app may have changed since.
+            let path = Path::new(OsStr::from_bytes(mount_point.as_bytes()));
+            if self.mount_points[..i].iter().any(|m| {
+                let above = Path::new(OsStr::from_bytes(m.as_bytes()));
+                path != above && path.starts_with(above)
+            }) {
+                continue;
+            }
+            let e = unsafe {
+                libc::umount2(mount_point.as_ptr(), libc::MNT_DETACH | libc::UMOUNT_NOFOLLOW)
+            };
+            if e != 0 && result.is_ok() {
+                result = Err(Error::Mount(mount_point.to_string_lossy().into_owned(),
+                                          io::Error::last_os_error()));
+            }
+        }
+        result
+    }
+
+    pub fn isolators(&self) -> Vec<Isolator> {
+        match self.json.app {
+            None => Vec::new(),
+            Some(ref a) => a.isolators_or_empty()
+        }
+    }
+
+    pub fn ports(&self) -> Vec<app::Port> {
+        self.json.ports()
+    }
+
+    /// The UID and GID the app runs as, looked up in its mounted rootfs.
+    pub fn identity(&self, rootfs: &str) -> io::Result<(u32, u32)> {
+        match self.json.app {
+            None => Ok((0, 0)),
+            Some(ref a) => a.identity(rootfs)
+        }
     }
 
-    pub fn exec(&self, dir: &str, pod_uuid: Uuid) -> (Option<Command>, Option<Command>, Option<Command>) {
-        let app_name = self.json.name.split('/').last().unwrap();
+    pub fn exec(&self, dir: &str, app_name: &str, options: &ExecOptions,
+                sockets: &[ActivatedSocket])
+                -> (Option<Command>, Option<Command>, Option<Command>) {
         match self.json.app {
             None => (None, None, None),
-            Some(ref a) => a.exec_app(dir, app_name, pod_uuid)
+            Some(ref a) => a.exec_app(dir, app_name, options, sockets)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use crate::util::test_dir;
+
+    fn write_error(path: &str) -> Option<i32> {
+        write(path, "x").err().and_then(|e| e.raw_os_error())
+    }
+
+    /// Mounts a read-only and a writable volume into a rootfs made
+    /// read-only, in a mount namespace of the test's own. Only root can.
+    #[test]
+    fn read_only_volumes_and_rootfs() {
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("skipped: mounting takes root");
+            return;
+        }
+        let dir = format!("{}/", test_dir("mounts").display());
+        let rootfs = format!("{}rootfs/", dir);
+        let etc = format!("{}etc/", dir);
+        for d in &[&rootfs, &etc, &format!("{}ro", dir), &format!("{}rw", dir)] {
+            create_dir_all(d).unwrap();
+        }
+        write(format!("{}hosts", etc), "127.0.0.1 localhost\n").unwrap();
+
+        // Each test runs on a thread of its own, whose mount namespace this
+        // leaves the host's alone.
+        unsafe {
+            assert_eq!(libc::unshare(libc::CLONE_NEWNS), 0);
+            assert_eq!(libc::mount(ptr::null(), c"/".as_ptr(), ptr::null(),
+                                   libc::MS_REC | libc::MS_PRIVATE, ptr::null()), 0);
+        }
+
+        let json : AciJson = serde_json::from_str(r#"{
+            "acKind": "ImageManifest", "acVersion": "0.8.9", "name": "example.com/test",
+            "app": {"exec": ["/bin/true"], "user": "0", "group": "0",
+                    "mountPoints": [{"name": "ro", "path": "/ro", "readOnly": true}]}
+        }"#).unwrap();
+        let mounts = vec![
+            VolumeMount {
+                source: format!("{}ro", dir),
+                path: String::from("/ro"),
+                read_only: true
+            },
+            VolumeMount {
+                source: format!("{}rw", dir),
+                path: String::from("/srv/rw"),
+                read_only: false
+            }
+        ];
+        let mut aci = Aci::new(json);
+        aci.mount_volumes(&rootfs, &mounts, None, &etc, true).unwrap();
+
+        assert_eq!(write_error(&format!("{}ro/file", rootfs)), Some(libc::EROFS));
+        assert_eq!(write_error(&format!("{}file", rootfs)), Some(libc::EROFS));
+        assert_eq!(write_error(&format!("{}etc/hosts", rootfs)), Some(libc::EROFS));
+        assert_eq!(write_error(&format!("{}srv/rw/file", rootfs)), None);
+        assert!(Path::new(&format!("{}rw/file", dir)).exists());
+        assert!(Path::new(&format!("{}dev/null", rootfs)).exists());
+
+        aci.unmount_volumes().unwrap();
+        assert_eq!(write_error(&format!("{}file", rootfs)), None);
+        assert!(!Path::new(&format!("{}dev/null", rootfs)).exists());
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,14 +3,21 @@
 pub struct MountPoint {
     pub name: String,
     pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     readOnly: Option<bool>
 }
 
 impl MountPoint {
     pub fn read_only(&self) -> bool {
-        match self.readOnly {
-            None => false,
-            Some(b) => b
-        }
+        self.readOnly.unwrap_or_default()
     }
 }
+
+/// A volume resolved to a directory on the host, ready to be mounted at
+/// `path` inside an app's rootfs.
+#[derive(Clone)]
+pub struct VolumeMount {
+    pub source: String,
+    pub path: String,
+    pub read_only: bool
+}
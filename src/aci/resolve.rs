@@ -0,0 +1,148 @@
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+/// What to make a path that doesn't exist yet into.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Dir,
+    File
+}
+
+fn c_path(path: &str) -> io::Result<CString> {
+    CString::new(path).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+                                                  format!("bad path {:?}", path)))
+}
+
+fn open_root(root: &str) -> io::Result<OwnedFd> {
+    let root = c_path(root)?;
+    let fd = unsafe {
+        libc::open(root.as_ptr(), libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn open(dir: &OwnedFd, path: &str, flags: libc::c_int,
+        mode: libc::mode_t) -> io::Result<OwnedFd> {
+    let path = c_path(path)?;
+    let mut how : libc::open_how = unsafe { mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.mode = mode as u64;
+    how.resolve = libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS;
+    let fd = unsafe {
+        libc::syscall(libc::SYS_openat2, dir.as_raw_fd(), path.as_ptr(),
+                      &how as *const libc::open_how, mem::size_of::<libc::open_how>())
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Opens `path` inside the directory `root` as if `root` were `/`, so that
+/// neither `..` nor the symlinks in it lead anywhere else. Whatever of the
+/// path is missing is made, the last of it as `kind`. The descriptor is
+/// `O_PATH`, good for mounting on through `/proc/self/fd`.
+pub fn open_in_root(root: &str, path: &str, kind: Kind) -> io::Result<OwnedFd> {
+    let root = open_root(root)?;
+    let names : Vec<&str> = path.split('/').filter(|n| !n.is_empty()).collect();
+    let mut dir = open(&root, ".", libc::O_PATH | libc::O_DIRECTORY, 0)?;
+    let mut walked = String::from(".");
+    for (i, name) in names.iter().enumerate() {
+        walked.push('/');
+        walked.push_str(name);
+        if i + 1 == names.len() && kind == Kind::File {
+            return match open(&root, &walked, libc::O_PATH, 0) {
+                // Made through the root so that a dangling symlink is
+                // followed inside it.
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) =>
+                    open(&root, &walked, libc::O_CREAT | libc::O_RDONLY | libc::O_NOCTTY, 0o644),
+                r => r
+            };
+        }
+        dir = match open(&root, &walked, libc::O_PATH | libc::O_DIRECTORY, 0) {
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                let name_c = c_path(name)?;
+                if unsafe { libc::mkdirat(dir.as_raw_fd(), name_c.as_ptr(), 0o755) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                open(&root, &walked, libc::O_PATH | libc::O_DIRECTORY, 0)?
+            }
+            r => r?
+        };
+    }
+    Ok(dir)
+}
+
+/// Opens `path` inside the directory `root` with `flags` as `open_in_root`
+/// resolves it, but only if it is already there.
+pub fn open_existing_in_root(root: &str, path: &str,
+                             flags: libc::c_int) -> io::Result<OwnedFd> {
+    let root = open_root(root)?;
+    let path = path.trim_start_matches('/');
+    open(&root, if path.is_empty() { "." } else { path }, flags, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, read_link, remove_dir_all};
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    use crate::util::test_dir;
+
+    /// Makes an empty directory to play the root in.
+    fn root(name: &str) -> PathBuf {
+        test_dir(&format!("resolve-{}", name))
+    }
+
+    fn opened(fd: &OwnedFd) -> PathBuf {
+        read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap()
+    }
+
+    #[test]
+    fn makes_what_is_missing() {
+        let dir = root("missing");
+        let fd = open_in_root(dir.to_str().unwrap(), "/a/b/c", Kind::Dir).unwrap();
+        assert_eq!(opened(&fd), dir.join("a/b/c"));
+        assert!(dir.join("a/b/c").is_dir());
+        let fd = open_in_root(dir.to_str().unwrap(), "etc/hosts", Kind::File).unwrap();
+        assert_eq!(opened(&fd), dir.join("etc/hosts"));
+        assert!(dir.join("etc/hosts").is_file());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stays_in_root() {
+        let dir = root("escape");
+        create_dir_all(dir.join("real/etc")).unwrap();
+        symlink("../../../../../../../../..", dir.join("up")).unwrap();
+        symlink("/real/etc", dir.join("etc")).unwrap();
+        symlink("real/../up/real/etc/../../up", dir.join("chain")).unwrap();
+
+        let fd = open_in_root(dir.to_str().unwrap(), "up/tmp", Kind::Dir).unwrap();
+        assert_eq!(opened(&fd), dir.join("tmp"));
+        let fd = open_in_root(dir.to_str().unwrap(), "/../../etc/hosts", Kind::File).unwrap();
+        assert_eq!(opened(&fd), dir.join("real/etc/hosts"));
+        let fd = open_in_root(dir.to_str().unwrap(), "chain/up/dev", Kind::Dir).unwrap();
+        assert_eq!(opened(&fd), dir.join("dev"));
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn follows_dangling_symlinks_inside_root() {
+        let dir = root("dangling");
+        create_dir_all(dir.join("run")).unwrap();
+        create_dir_all(dir.join("etc")).unwrap();
+        symlink("/run/resolv.conf", dir.join("etc/resolv.conf")).unwrap();
+        let fd = open_in_root(dir.to_str().unwrap(), "etc/resolv.conf", Kind::File).unwrap();
+        assert_eq!(opened(&fd), dir.join("run/resolv.conf"));
+        remove_dir_all(&dir).unwrap();
+    }
+}
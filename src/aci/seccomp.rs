@@ -0,0 +1,310 @@
+use std::io;
+
+use crate::error::Error;
+
+use super::Isolator;
+use super::syscalls::SYSCALLS;
+
+const RETAIN_SET : &str = "os/linux/seccomp-retain-set";
+const REMOVE_SET : &str = "os/linux/seccomp-remove-set";
+const ALL : &str = "@appc.io/all";
+const EMPTY : &str = "@appc.io/empty";
+const DOCKER_BLACKLIST : &str = "@docker/default-blacklist";
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH : u32 = 0xc000003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH : u32 = 0xc00000b7;
+
+/// Where `nr` and `arch` are in the `seccomp_data` a filter looks at.
+const NR_OFFSET : u32 = 0;
+const ARCH_OFFSET : u32 = 4;
+/// Syscalls of the x32 ABI have this bit set on x86_64.
+const X32_SYSCALL_BIT : u32 = 0x40000000;
+
+/// The syscalls Docker refuses by default, which is what apps get when their
+/// image has no seccomp isolator.
+const DEFAULT_BLACKLIST : [&str; 50] = [
+    "_sysctl", "add_key", "adjtimex", "bpf", "clock_adjtime", "clock_settime",
+    "create_module", "delete_module", "finit_module", "get_kernel_syms",
+    "get_mempolicy", "init_module", "ioperm", "iopl", "kcmp",
+    "kexec_file_load", "kexec_load", "keyctl", "lookup_dcookie", "mbind",
+    "mount", "move_pages", "name_to_handle_at", "nfsservctl",
+    "open_by_handle_at", "perf_event_open", "personality", "pivot_root",
+    "process_vm_readv", "process_vm_writev", "ptrace", "query_module",
+    "quotactl", "reboot", "request_key", "set_mempolicy", "setns",
+    "settimeofday", "stime", "swapoff", "swapon", "sysfs", "umount",
+    "umount2", "unshare", "uselib", "userfaultfd", "ustat", "vm86", "vm86old"
+];
+
+const ERRNOS : [(&str, libc::c_int); 12] = [
+    ("EPERM", libc::EPERM), ("ENOENT", libc::ENOENT), ("EIO", libc::EIO),
+    ("EBADF", libc::EBADF), ("EAGAIN", libc::EAGAIN), ("ENOMEM", libc::ENOMEM),
+    ("EACCES", libc::EACCES), ("EFAULT", libc::EFAULT),
+    ("EINVAL", libc::EINVAL), ("ENOSYS", libc::ENOSYS),
+    ("ENOTSUP", libc::ENOTSUP), ("EOPNOTSUPP", libc::EOPNOTSUPP)
+];
+
+#[derive(Deserialize)]
+struct SyscallSet {
+    set: Vec<String>,
+    errno: Option<String>
+}
+
+/// A BPF program refusing the syscalls an app may not make.
+#[derive(Clone)]
+pub struct Seccomp {
+    filter: Vec<libc::sock_filter>
+}
+
+fn invalid(isolator: &Isolator, why: String) -> Error {
+    Error::Isolator(isolator.name.clone(), io::Error::new(io::ErrorKind::InvalidData, why))
+}
+
+fn syscall_number(name: &str) -> Option<u32> {
+    SYSCALLS.iter().find(|s| s.0 == name).map(|s| s.1 as u32)
+}
+
+/// Turns syscall names and groups into syscall numbers. Names unknown to
+/// this architecture are only fine in the groups, which cover several.
+fn numbers(isolator: &Isolator, names: &[String]) -> Result<Vec<u32>, Error> {
+    let mut numbers = Vec::new();
+    for name in names {
+        match name.as_ref() {
+            ALL => numbers.extend(SYSCALLS.iter().map(|s| s.1 as u32)),
+            EMPTY => {},
+            DOCKER_BLACKLIST => numbers.extend(
+                DEFAULT_BLACKLIST.iter().filter_map(|n| syscall_number(n))),
+            _ => match syscall_number(name) {
+                Some(nr) => numbers.push(nr),
+                None => return Err(invalid(isolator, format!("unknown syscall {}", name)))
+            }
+        }
+    }
+    numbers.sort_unstable();
+    numbers.dedup();
+    Ok(numbers)
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k
+    }
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+/// Builds a program returning `listed` for the syscalls in `numbers` and
+/// `unlisted` for the others, killing processes of any other ABI. Those in
+/// `allowed` are let through before anything else.
+fn build(allowed: &[u32], numbers: &[u32], listed: u32,
+         unlisted: u32) -> Vec<libc::sock_filter> {
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let jeq = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+    let ret = libc::BPF_RET | libc::BPF_K;
+
+    let mut filter = vec![
+        stmt(load, ARCH_OFFSET),
+        jump(jeq, AUDIT_ARCH, 1, 0),
+        stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(load, NR_OFFSET)
+    ];
+    if cfg!(target_arch = "x86_64") {
+        let jge = libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K;
+        filter.push(jump(jge, X32_SYSCALL_BIT, 0, 1));
+        filter.push(stmt(ret, libc::SECCOMP_RET_KILL_PROCESS));
+    }
+    for nr in allowed {
+        filter.push(jump(jeq, *nr, 0, 1));
+        filter.push(stmt(ret, libc::SECCOMP_RET_ALLOW));
+    }
+    for nr in numbers {
+        filter.push(jump(jeq, *nr, 0, 1));
+        filter.push(stmt(ret, listed));
+    }
+    filter.push(stmt(ret, unlisted));
+    filter
+}
+
+impl Seccomp {
+    /// Builds the filter of the `seccomp-retain-set` or `seccomp-remove-set`
+    /// isolator among `isolators`, which can't both be given. Refused
+    /// syscalls fail with the isolator's `errno`, or kill the process if it
+    /// has none. Without either isolator, Docker's default blacklist fails
+    /// with `EPERM`. Without `no_new_privileges`, the filter has to be
+    /// installed while fyc still holds `CAP_SYS_ADMIN`, so `capget` and
+    /// `capset` are let through for fyc to drop it.
+    pub fn from_isolators(isolators: &[Isolator],
+                          no_new_privileges: bool) -> Result<Seccomp, Error> {
+        let allowed = if no_new_privileges {
+            Vec::new()
+        } else {
+            vec![libc::SYS_capget as u32, libc::SYS_capset as u32]
+        };
+        let mut filter = None;
+        for isolator in isolators {
+            if isolator.name != RETAIN_SET && isolator.name != REMOVE_SET {
+                continue;
+            }
+            if filter.is_some() {
+                return Err(Error::Isolator(isolator.name.clone(), io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "only one seccomp isolator may be given")));
+            }
+            let set : SyscallSet = serde_json::from_value(isolator.value.clone())
+                .map_err(|e| Error::Isolator(isolator.name.clone(), e.into()))?;
+            let refuse = match set.errno {
+                None => libc::SECCOMP_RET_KILL_PROCESS,
+                Some(ref name) => match ERRNOS.iter().find(|e| e.0 == name) {
+                    Some(e) => libc::SECCOMP_RET_ERRNO | e.1 as u32,
+                    None => return Err(invalid(isolator, format!("unknown errno {}", name)))
+                }
+            };
+            let numbers = numbers(isolator, &set.set)?;
+            filter = Some(if isolator.name == RETAIN_SET {
+                build(&allowed, &numbers, libc::SECCOMP_RET_ALLOW, refuse)
+            } else {
+                build(&allowed, &numbers, refuse, libc::SECCOMP_RET_ALLOW)
+            });
+        }
+        let filter = filter.unwrap_or_else(|| {
+            let numbers : Vec<u32> = DEFAULT_BLACKLIST.iter()
+                .filter_map(|n| syscall_number(n))
+                .collect();
+            build(&allowed, &numbers, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+                  libc::SECCOMP_RET_ALLOW)
+        });
+        Ok(Seccomp {
+            filter
+        })
+    }
+
+    /// Installs the filter on the calling process, for good. That takes
+    /// `CAP_SYS_ADMIN` unless no-new-privileges is set.
+    pub unsafe fn apply(&self) -> io::Result<()> {
+        let program = libc::sock_fprog {
+            len: self.filter.len() as libc::c_ushort,
+            filter: self.filter.as_ptr() as *mut libc::sock_filter
+        };
+        if libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER,
+                       &program as *const libc::sock_fprog) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ERRNO_EPERM : u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    /// Runs `filter` on a syscall as the kernel would, for the little of
+    /// BPF that `build` uses.
+    fn run(filter: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = filter[pc];
+            let code = instruction.code as u32;
+            pc += 1;
+            if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS {
+                accumulator = match instruction.k {
+                    NR_OFFSET => nr,
+                    ARCH_OFFSET => arch,
+                    k => panic!("load from {}", k)
+                };
+            } else if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K {
+                let taken = accumulator == instruction.k;
+                pc += if taken { instruction.jt } else { instruction.jf } as usize;
+            } else if code == libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K {
+                let taken = accumulator >= instruction.k;
+                pc += if taken { instruction.jt } else { instruction.jf } as usize;
+            } else if code == libc::BPF_RET | libc::BPF_K {
+                return instruction.k;
+            } else {
+                panic!("unexpected instruction {:#x}", code);
+            }
+        }
+    }
+
+    fn nr(name: &str) -> u32 {
+        syscall_number(name).unwrap()
+    }
+
+    fn seccomp(isolators: &str, no_new_privileges: bool) -> Result<Seccomp, Error> {
+        let isolators : Vec<Isolator> = serde_json::from_str(isolators).unwrap();
+        Seccomp::from_isolators(&isolators, no_new_privileges)
+    }
+
+    fn program(isolators: &str, no_new_privileges: bool) -> Vec<libc::sock_filter> {
+        seccomp(isolators, no_new_privileges).ok().unwrap().filter
+    }
+
+    const RETAIN : &str = r#"[{"name": "os/linux/seccomp-retain-set",
+        "value": {"set": ["read", "write", "execve"], "errno": "EPERM"}}]"#;
+
+    #[test]
+    fn retain_set_refuses_the_rest() {
+        let filter = program(RETAIN, true);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("read")), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("execve")), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("getpid")), ERRNO_EPERM);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("capset")), ERRNO_EPERM);
+    }
+
+    #[test]
+    fn dropping_capabilities_is_let_through_without_no_new_privileges() {
+        let filter = program(RETAIN, false);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("capget")), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("capset")), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("setuid")), ERRNO_EPERM);
+
+        let filter = program(r#"[{"name": "os/linux/seccomp-remove-set",
+            "value": {"set": ["capset"]}}]"#, false);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("capset")), libc::SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn remove_set_kills_without_errno() {
+        let filter = program(r#"[{"name": "os/linux/seccomp-remove-set",
+            "value": {"set": ["@docker/default-blacklist", "chmod"]}}]"#, true);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("chmod")), libc::SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("mount")), libc::SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("read")), libc::SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn default_is_docker_blacklist() {
+        let filter = program("[]", false);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("mount")), ERRNO_EPERM);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("unshare")), ERRNO_EPERM);
+        assert_eq!(run(&filter, AUDIT_ARCH, nr("read")), libc::SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn other_abis_are_killed() {
+        let filter = program("[]", true);
+        assert_eq!(run(&filter, 0x40000003, nr("read")), libc::SECCOMP_RET_KILL_PROCESS);
+        if cfg!(target_arch = "x86_64") {
+            assert_eq!(run(&filter, AUDIT_ARCH, X32_SYSCALL_BIT | nr("read")),
+                       libc::SECCOMP_RET_KILL_PROCESS);
+        }
+    }
+
+    #[test]
+    fn bad_isolators_are_refused() {
+        assert!(seccomp(r#"[{"name": "os/linux/seccomp-retain-set", "value": {"set": []}},
+            {"name": "os/linux/seccomp-remove-set", "value": {"set": []}}]"#, true).is_err());
+        assert!(seccomp(r#"[{"name": "os/linux/seccomp-retain-set",
+            "value": {"set": ["nosuchcall"]}}]"#, true).is_err());
+        assert!(seccomp(r#"[{"name": "os/linux/seccomp-retain-set",
+            "value": {"set": ["read"], "errno": "EWHATEVER"}}]"#, true).is_err());
+    }
+}
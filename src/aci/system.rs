@@ -0,0 +1,200 @@
+use std::ffi::{CStr, CString};
+use std::fs::{create_dir_all, read_link, set_permissions, File, Permissions};
+use std::io;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+use crate::error::Error;
+
+use super::resolve::{open_in_root, Kind};
+use super::VolumeMount;
+
+/// The character devices every app gets in `/dev`, by major and minor.
+const DEVICES : [(&str, u32, u32); 6] = [
+    ("null", 1, 3), ("zero", 1, 5), ("full", 1, 7), ("random", 1, 8),
+    ("urandom", 1, 9), ("tty", 5, 0)
+];
+
+const DEV_LINKS : [(&str, &str); 5] = [
+    ("fd", "/proc/self/fd"), ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"), ("stderr", "/proc/self/fd/2"),
+    ("ptmx", "pts/ptmx")
+];
+
+/// The files of a pod's `etc` directory that are bound over an app's.
+const ETC_FILES : [&str; 3] = ["hosts", "hostname", "resolv.conf"];
+
+fn with_path(path: &str, e: io::Error) -> Error {
+    Error::Io(io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn mount(source: Option<&str>, target: &str, fs_type: Option<&str>,
+         flags: libc::c_ulong, data: Option<&str>) -> io::Result<()> {
+    let source = source.map(|s| CString::new(s).unwrap());
+    let fs_type = fs_type.map(|s| CString::new(s).unwrap());
+    let data = data.map(|s| CString::new(s).unwrap());
+    let target = CString::new(target).unwrap();
+    let e = unsafe {
+        libc::mount(source.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+                    target.as_ptr(),
+                    fs_type.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+                    flags,
+                    data.as_ref().map_or(ptr::null(), |s| s.as_ptr()) as *const libc::c_void)
+    };
+    if e != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Mounts onto `target`, which must exist, and remembers it for unmounting.
+fn mount_on(source: Option<&str>, target: &str, fs_type: Option<&str>,
+            flags: libc::c_ulong, data: Option<&str>,
+            mount_points: &mut Vec<CString>) -> Result<(), Error> {
+    mount(source, target, fs_type, flags, data)
+        .map_err(|e| Error::Mount(String::from(target), e))?;
+    mount_points.push(CString::new(target).unwrap());
+    Ok(())
+}
+
+/// Mounts onto `path` in the rootfs at `app_path`, looked up as the app
+/// would and made as `kind` if missing, so that no symlink in the image gets
+/// it mounted elsewhere on the host. `mount` is given the path to mount on.
+/// Returns where the mount is, which is remembered for unmounting.
+pub fn mount_in_root<F>(app_path: &str, path: &str, kind: Kind,
+                        mount_points: &mut Vec<CString>, mount: F) -> Result<String, Error>
+    where F: FnOnce(&str) -> io::Result<()> {
+    let failed = |e| Error::Mount(format!("{}{}", app_path, path.trim_start_matches('/')), e);
+    let fd = open_in_root(app_path, path, kind).map_err(failed)?;
+    let fd_path = format!("/proc/self/fd/{}", fd.as_raw_fd());
+    let target = read_link(&fd_path).map_err(failed)?;
+    let target = target.to_str().map(String::from).ok_or_else(|| failed(io::Error::new(
+        io::ErrorKind::InvalidData, "is not valid UTF-8")))?;
+    mount(&fd_path).map_err(failed)?;
+    mount_points.push(CString::new(target.clone()).unwrap());
+    Ok(target)
+}
+
+/// Makes the bind mount at `target` read-only, which can't be done while
+/// binding.
+pub fn remount_read_only(target: &str) -> Result<(), Error> {
+    let target_c = CString::new(target).unwrap();
+    let e = unsafe {
+        libc::mount(ptr::null(), target_c.as_ptr(), ptr::null(),
+                    libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY, ptr::null())
+    };
+    if e != 0 {
+        return Err(Error::Mount(String::from(target), io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn mount_dir(source: Option<&str>, target: &str, fs_type: Option<&str>,
+             flags: libc::c_ulong, data: Option<&str>,
+             mount_points: &mut Vec<CString>) -> Result<(), Error> {
+    create_dir_all(target).map_err(|e| with_path(target, e))?;
+    mount_on(source, target, fs_type, flags, data, mount_points)
+}
+
+/// Fills the tmpfs at `dev` with the devices, links, pseudo-terminals and
+/// shared memory the spec has every app get.
+fn populate_dev(dev: &str, mount_points: &mut Vec<CString>) -> Result<(), Error> {
+    for &(name, major, minor) in DEVICES.iter() {
+        let path = format!("{}/{}", dev, name);
+        let c_path = CString::new(path.clone()).unwrap();
+        let e = unsafe {
+            libc::mknod(c_path.as_ptr(), libc::S_IFCHR | 0o666,
+                        libc::makedev(major, minor))
+        };
+        if e != 0 {
+            return Err(with_path(&path, io::Error::last_os_error()));
+        }
+        // mknod heeds the umask.
+        set_permissions(&path, Permissions::from_mode(0o666))
+            .map_err(|e| with_path(&path, e))?;
+    }
+    for &(name, target) in DEV_LINKS.iter() {
+        let path = format!("{}/{}", dev, name);
+        symlink(target, &path).map_err(|e| with_path(&path, e))?;
+    }
+
+    mount_dir(Some("devpts"), &format!("{}/pts", dev), Some("devpts"),
+              libc::MS_NOSUID | libc::MS_NOEXEC,
+              Some("newinstance,ptmxmode=0666,mode=0620"), mount_points)?;
+    mount_dir(Some("shm"), &format!("{}/shm", dev), Some("tmpfs"),
+              libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+              Some("mode=1777,size=65536k"), mount_points)?;
+
+    // The terminal fyc runs on, if any, is the app's console.
+    if unsafe { libc::isatty(0) } == 1 {
+        let tty = unsafe { libc::ttyname(0) };
+        if !tty.is_null() {
+            let tty = unsafe { CStr::from_ptr(tty) }.to_string_lossy().into_owned();
+            let console = format!("{}/console", dev);
+            File::create(&console).map_err(|e| with_path(&console, e))?;
+            mount_on(Some(&tty), &console, None, libc::MS_BIND, None, mount_points)?;
+        }
+    }
+    Ok(())
+}
+
+/// Mounts `/proc`, a read-only `/sys` and a minimal `/dev` into the rootfs
+/// at `app_path`. `/proc` is that of `proc_dir` if given, so as to show the
+/// pod's own processes, or else of the host's PID namespace. Any of them
+/// that `volumes` mount something at is left to the volume.
+pub fn mount_system_volumes(app_path: &str, proc_dir: Option<&str>,
+                            volumes: &[VolumeMount],
+                            mount_points: &mut Vec<CString>) -> Result<(), Error> {
+    let taken = |dir: &str| volumes.iter().any(|v| v.path.trim_matches('/') == dir);
+
+    if !taken("proc") {
+        match proc_dir {
+            Some(dir) => mount_in_root(app_path, "proc", Kind::Dir, mount_points, |t| {
+                mount(Some(dir), t, None, libc::MS_BIND, None)
+            })?,
+            None => mount_in_root(app_path, "proc", Kind::Dir, mount_points, |t| {
+                mount(Some("proc"), t, Some("proc"),
+                      libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC, None)
+            })?
+        };
+    }
+
+    if !taken("sys") {
+        mount_in_root(app_path, "sys", Kind::Dir, mount_points, |t| {
+            mount(Some("sysfs"), t, Some("sysfs"),
+                  libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC, None)
+        })?;
+    }
+
+    if !taken("dev") {
+        // The tmpfs is fresh, so what goes in it can be made by path.
+        let dev = mount_in_root(app_path, "dev", Kind::Dir, mount_points, |t| {
+            mount(Some("tmpfs"), t, Some("tmpfs"), libc::MS_NOSUID | libc::MS_STRICTATIME,
+                  Some("mode=755,size=65536k"))
+        })?;
+        populate_dev(&dev, mount_points)?;
+    }
+    Ok(())
+}
+
+/// Binds the pod's `hosts`, `hostname` and `resolv.conf` from `etc_dir`
+/// read-only over those in the rootfs at `app_path`, except where `volumes`
+/// mount something else.
+pub fn mount_etc_files(app_path: &str, etc_dir: &str, volumes: &[VolumeMount],
+                       mount_points: &mut Vec<CString>) -> Result<(), Error> {
+    for name in ETC_FILES.iter() {
+        let source = format!("{}{}", etc_dir, name);
+        let etc_path = format!("etc/{}", name);
+        if !Path::new(&source).exists() ||
+            volumes.iter().any(|v| v.path.trim_matches('/') == etc_path) {
+            continue;
+        }
+        let target = mount_in_root(app_path, &etc_path, Kind::File, mount_points, |t| {
+            mount(Some(&source), t, None, libc::MS_BIND, None)
+        })?;
+        remount_read_only(&target)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use std::fs::{create_dir, create_dir_all, remove_dir, write};
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::aci::Isolator;
+use crate::error::Error;
+
+pub const DEFAULT_CGROUP_ROOT : &str = "/sys/fs/cgroup/fyc";
+const MEMORY_ISOLATOR : &str = "resource/memory";
+const CPU_ISOLATOR : &str = "resource/cpu";
+const CONTROLLERS : &str = "+memory +cpu";
+const CPU_PERIOD : u64 = 100000;
+const REMOVE_ATTEMPTS : u32 = 100;
+
+#[derive(Deserialize)]
+struct Resource {
+    request: Option<Value>,
+    limit: Option<Value>
+}
+
+/// A cgroup v2 directory made by fyc, for a pod or one of its apps.
+pub struct Cgroup {
+    path: String
+}
+
+fn invalid(isolator: &Isolator, why: &str) -> Error {
+    Error::Isolator(isolator.name.clone(),
+                    IoError::new(ErrorKind::InvalidData, String::from(why)))
+}
+
+/// Parses a quantity such as `512Mi`, `1.5G` or `250m` (thousandths).
+fn parse_quantity(quantity: &Value) -> Option<f64> {
+    let s = match *quantity {
+        Value::Number(ref n) => return n.as_f64(),
+        Value::String(ref s) => s,
+        _ => return None
+    };
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let multiplier = match suffix {
+        "" => 1.0,
+        "m" => 1e-3,
+        "k" | "K" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        "Ki" => 1024.0,
+        "Mi" => 1024.0 * 1024.0,
+        "Gi" => 1024.0 * 1024.0 * 1024.0,
+        "Ti" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        "Pi" => 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        "Ei" => 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None
+    };
+    number.parse::<f64>().ok().map(|n| n * multiplier)
+}
+
+fn parse_resource(isolator: &Isolator) -> Result<(Option<f64>, Option<f64>), Error> {
+    let resource : Resource = serde_json::from_value(isolator.value.clone())
+        .map_err(|e| Error::Isolator(isolator.name.clone(), e.into()))?;
+    let mut quantities = Vec::new();
+    for quantity in [resource.request, resource.limit].iter() {
+        quantities.push(match *quantity {
+            None => None,
+            Some(ref q) => match parse_quantity(q) {
+                Some(n) if n >= 0.0 => Some(n),
+                _ => return Err(invalid(isolator, "is not a valid quantity"))
+            }
+        });
+    }
+    Ok((quantities[0], quantities[1]))
+}
+
+/// Turns an isolator into the cgroup files and values that enforce it, or
+/// nothing if it isn't a resource isolator.
+fn limits(isolator: &Isolator) -> Result<Vec<(&'static str, String)>, Error> {
+    let mut files = Vec::new();
+    if isolator.name == MEMORY_ISOLATOR {
+        let (request, limit) = parse_resource(isolator)?;
+        if let Some(bytes) = request {
+            files.push(("memory.low", format!("{}", bytes.round() as u64)));
+        }
+        if let Some(bytes) = limit {
+            files.push(("memory.max", format!("{}", bytes.round() as u64)));
+        }
+    } else if isolator.name == CPU_ISOLATOR {
+        let (request, limit) = parse_resource(isolator)?;
+        if let Some(cores) = request {
+            // The weight that matches the request's share of CPU time under
+            // cgroup v1 (1024 per core), as runc converts it.
+            let shares = ((cores * 1024.0).round() as u64).max(2);
+            let weight = (1 + ((shares - 2) * 9999) / 262142).min(10000);
+            files.push(("cpu.weight", format!("{}", weight)));
+        }
+        if let Some(cores) = limit {
+            let quota = ((cores * CPU_PERIOD as f64).round() as u64).max(1000);
+            files.push(("cpu.max", format!("{} {}", quota, CPU_PERIOD)));
+        }
+    }
+    Ok(files)
+}
+
+fn is_resource(isolator: &Isolator) -> bool {
+    isolator.name == MEMORY_ISOLATOR || isolator.name == CPU_ISOLATOR
+}
+
+/// Fails if any of `isolators` needs a cgroup, for when there is none.
+pub fn check_unneeded(isolators: &[Isolator]) -> Result<(), Error> {
+    match isolators.iter().find(|i| is_resource(i)) {
+        Some(i) => Err(Error::Isolator(i.name.clone(), IoError::new(
+            ErrorKind::Unsupported, "needs a cgroup v2 hierarchy fyc can make cgroups in"))),
+        None => Ok(())
+    }
+}
+
+impl Cgroup {
+    /// Creates the cgroup of a pod under `root`, or returns `None` if
+    /// `root` isn't in a cgroup v2 hierarchy or fyc may not make cgroups
+    /// there. Only isolators needing one stop the pod then.
+    pub fn create_pod(root: &str, pod_uuid: &str) -> Option<Cgroup> {
+        let in_hierarchy = Path::new(root).parent()
+            .is_some_and(|p| p.join("cgroup.controllers").exists());
+        if !in_hierarchy || create_dir_all(root).is_err() {
+            return None;
+        }
+        let root = Cgroup {
+            path: String::from(root)
+        };
+        root.enable_controllers();
+        let pod = root.create_child(pod_uuid).ok()?;
+        pod.enable_controllers();
+        Some(pod)
+    }
+
+    /// Creates a cgroup below this one, such as that of an app in the pod.
+    pub fn create_child(&self, name: &str) -> Result<Cgroup, Error> {
+        let path = format!("{}/{}", self.path, name);
+        create_dir(&path).map_err(|e| Error::Cgroup(path.clone(), e))?;
+        Ok(Cgroup {
+            path
+        })
+    }
+
+    /// Lets the cgroups below this one have limits of their own. Controllers
+    /// that can't be had only matter once a limit needs them, which fails
+    /// then.
+    fn enable_controllers(&self) {
+        let _ = write(format!("{}/cgroup.subtree_control", self.path), CONTROLLERS);
+    }
+
+    /// Enforces the `resource/memory` and `resource/cpu` isolators among
+    /// `isolators`, leaving the others to someone else.
+    pub fn set_limits(&self, isolators: &[Isolator]) -> Result<(), Error> {
+        for isolator in isolators {
+            for (file, value) in limits(isolator)? {
+                let path = format!("{}/{}", self.path, file);
+                // cgroupfs doesn't say much more than EACCES otherwise.
+                if !Path::new(&path).exists() {
+                    return Err(Error::Isolator(isolator.name.clone(), IoError::new(
+                        ErrorKind::Unsupported,
+                        format!("no {} controller for {}", file.split('.').next().unwrap(),
+                                self.path))));
+                }
+                write(path, value)
+                    .map_err(|e| Error::Isolator(isolator.name.clone(), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The file a process writes `0` to in order to join the cgroup.
+    pub fn procs_file(&self) -> String {
+        format!("{}/cgroup.procs", self.path)
+    }
+
+    /// Kills whatever is still running in the cgroup and removes it.
+    pub fn remove(self) -> Result<(), Error> {
+        let _ = write(format!("{}/cgroup.kill", self.path), "1");
+        let mut attempts = 0;
+        loop {
+            match remove_dir(&self.path) {
+                Err(ref e) if e.raw_os_error() == Some(::libc::EBUSY) &&
+                    attempts < REMOVE_ATTEMPTS => {
+                    attempts += 1;
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(Error::Cgroup(self.path.clone(), e)),
+                Ok(()) => return Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantity(json: &str) -> Option<f64> {
+        parse_quantity(&serde_json::from_str(json).unwrap())
+    }
+
+    fn isolator(name: &str, value: &str) -> Isolator {
+        serde_json::from_str(&format!(r#"{{"name": "{}", "value": {}}}"#, name, value)).unwrap()
+    }
+
+    fn files(name: &str, value: &str) -> Vec<(&'static str, String)> {
+        limits(&isolator(name, value)).unwrap()
+    }
+
+    #[test]
+    fn quantities_parse() {
+        assert_eq!(quantity("2"), Some(2.0));
+        assert_eq!(quantity("0.5"), Some(0.5));
+        assert_eq!(quantity(r#""250m""#), Some(0.25));
+        assert_eq!(quantity(r#""1.5G""#), Some(1.5e9));
+        assert_eq!(quantity(r#""512Mi""#), Some(512.0 * 1024.0 * 1024.0));
+        assert_eq!(quantity(r#""2Ki""#), Some(2048.0));
+        assert_eq!(quantity(r#""1k""#), quantity(r#""1K""#));
+        for bad in &[r#""""#, r#""Mi""#, r#""-1""#, r#""1X""#, r#""1 Mi""#, "true", "[1]"] {
+            assert_eq!(quantity(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn memory_sets_low_and_max() {
+        assert_eq!(files(MEMORY_ISOLATOR, r#"{"request": "1Mi", "limit": "1.5Mi"}"#),
+                   vec![("memory.low", String::from("1048576")),
+                        ("memory.max", String::from("1572864"))]);
+        assert_eq!(files(MEMORY_ISOLATOR, r#"{"limit": 1000}"#),
+                   vec![("memory.max", String::from("1000"))]);
+    }
+
+    #[test]
+    fn cpu_converts_shares_and_quota() {
+        // One core is 1024 shares, which is weight 39 as runc has it.
+        assert_eq!(files(CPU_ISOLATOR, r#"{"request": 1, "limit": "250m"}"#),
+                   vec![("cpu.weight", String::from("39")),
+                        ("cpu.max", String::from("25000 100000"))]);
+        assert_eq!(files(CPU_ISOLATOR, r#"{"request": 0, "limit": "1m"}"#),
+                   vec![("cpu.weight", String::from("1")),
+                        ("cpu.max", String::from("1000 100000"))]);
+        assert_eq!(files(CPU_ISOLATOR, r#"{"request": 1000}"#),
+                   vec![("cpu.weight", String::from("10000"))]);
+        assert_eq!(files(CPU_ISOLATOR, r#"{"limit": 2}"#),
+                   vec![("cpu.max", String::from("200000 100000"))]);
+    }
+
+    #[test]
+    fn bad_resources_are_refused() {
+        assert!(limits(&isolator(MEMORY_ISOLATOR, r#"{"limit": -1}"#)).is_err());
+        assert!(limits(&isolator(CPU_ISOLATOR, r#"{"request": "fast"}"#)).is_err());
+        assert!(limits(&isolator(CPU_ISOLATOR, r#""1""#)).is_err());
+        assert!(files("os/linux/no-new-privileges", "true").is_empty());
+        assert!(check_unneeded(&[isolator(CPU_ISOLATOR, r#"{"limit": 1}"#)]).is_err());
+        assert!(check_unneeded(&[isolator("os/linux/no-new-privileges", "true")]).is_ok());
+    }
+}
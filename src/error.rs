@@ -0,0 +1,66 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can stop fyc from running a pod.
+#[derive(Debug)]
+pub enum Error {
+    /// The command line didn't make sense.
+    Usage(String),
+    /// An ACI could not be imported into the image store.
+    Extract(String, io::Error),
+    /// A pod or image manifest could not be read or parsed.
+    Manifest(String, io::Error),
+    /// No stored image matches what an app or dependency asked for.
+    Image(io::Error),
+    /// A pod volume could not be set up.
+    Volume(io::Error),
+    /// Mounting or unmounting something at the given path failed.
+    Mount(String, io::Error),
+    /// The named app, or one of its event handlers, could not be run.
+    Exec(String, io::Error),
+    /// The user or group the named app runs as could not be found.
+    Identity(String, io::Error),
+    /// The named pod port is invalid or could not be forwarded.
+    Port(String, io::Error),
+    /// The metadata service could not be started or the pod registered.
+    Metadata(io::Error),
+    /// The pod's namespaces could not be created or entered.
+    Namespace(io::Error),
+    /// The pod could not be put on its network.
+    Network(io::Error),
+    /// The cgroup at the given path could not be created or removed.
+    Cgroup(String, io::Error),
+    /// The named isolator is invalid or could not be enforced.
+    Isolator(String, io::Error),
+    Io(io::Error)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Usage(ref s) => write!(f, "{}", s),
+            Error::Extract(ref p, ref e) => write!(f, "could not extract {}: {}", p, e),
+            Error::Manifest(ref p, ref e) => write!(f, "bad manifest {}: {}", p, e),
+            Error::Image(ref e) => write!(f, "could not find image: {}", e),
+            Error::Volume(ref e) => write!(f, "could not set up volume: {}", e),
+            Error::Mount(ref p, ref e) => write!(f, "could not (un)mount {}: {}", p, e),
+            Error::Exec(ref a, ref e) => write!(f, "could not run app {}: {}", a, e),
+            Error::Identity(ref a, ref e) => write!(f, "no user or group for app {}: {}", a, e),
+            Error::Port(ref p, ref e) => write!(f, "port {}: {}", p, e),
+            Error::Metadata(ref e) => write!(f, "metadata service: {}", e),
+            Error::Namespace(ref e) => write!(f, "could not isolate the pod: {}", e),
+            Error::Network(ref e) => write!(f, "could not network the pod: {}", e),
+            Error::Cgroup(ref p, ref e) => write!(f, "cgroup {}: {}", p, e),
+            Error::Isolator(ref n, ref e) => write!(f, "isolator {}: {}", n, e),
+            Error::Io(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
@@ -12,27 +12,35 @@ extern crate serde_json;
 extern crate tar;
 extern crate uuid;
 
-use flate2::read::GzDecoder;
-
-use std::collections::HashMap;
 use std::collections::HashSet;
 use std::env;
-use std::fs::{create_dir, File};
-use std::io::{Error, Read};
-use std::path::Path;
-use std::sync::mpsc::{channel, Sender};
+use std::fs::{create_dir, remove_dir_all, File};
+use std::io;
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::process;
+use std::process::ExitStatus;
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::RwLock;
-use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
 
-use tokio::runtime::Handle;
+use tokio::runtime::Runtime;
 
-use tar::Archive;
+use cgroup::Cgroup;
+use error::Error;
+use namespace::{Namespaces, Sysctls};
+use network::Network;
 
 mod aci;
+mod cgroup;
+mod error;
 mod metadata;
+mod namespace;
+mod network;
 mod pod;
+mod portfwd;
+mod store;
 mod util;
 
 lazy_static! {
@@ -41,132 +49,608 @@ lazy_static! {
     };
 }
 
-const VOL_DIR : &'static str = "volumes/";
-const APP_DIR : &'static str = "apps/";
+const VOL_DIR : &str = "volumes/";
+const APP_DIR : &str = "apps/";
+const DEFAULT_IMAGE_DIR : &str = ".";
+const POD_VERSION : &str = "0.8.9";
+const PROC_DIR : &str = "proc/";
+const ETC_DIR : &str = "etc/";
+const STATUS_FILE : &str = "status";
+const USAGE : &str = "usage: fyc run [OPTIONS] --pod-manifest FILE | fyc run [OPTIONS] ACI...";
 
-fn untar(pstr: &String, mut dirstr: String) -> Result<String, Error> {
-    let p = Path::new(pstr);
-    dirstr.push_str(p.file_stem().unwrap().to_str().unwrap());
-    dirstr.push('/');
-    let ret = Ok(dirstr.clone());
-    let dir = Path::new(&dirstr);
-    create_dir(dir)?;
-    let file_result = File::open(p);
-    if let Ok(opened_file) = file_result {
-        let decoder = GzDecoder::new(opened_file);
-        Archive::new(decoder).unpack(dir)?;
-    } else if let Err(e) = file_result {
-        return Err(e);
-    }
-    ret
+/// Volume state shared by every app in a pod.
+struct PodVolumes {
+    defined: Vec<pod::Volume>,
+    dir: String,
+    created: HashSet<String>
 }
 
-fn run_aci(volumes: &mut HashSet<String>, pod_uuid: uuid::Uuid,
-           mut acidirstr: String, vol_dir: String) -> Result<(Sender<bool>, JoinHandle<()>), Error> {
-    let mut manifest_str = String::new();
-    File::open(Path::new(&acidirstr).join("manifest"))?.read_to_string(&mut manifest_str)?;
-    let mut manifest : aci::ACI = aci::ACI::new(&manifest_str)?;
+/// What every app of the running pod is set up with.
+struct PodContext<'a> {
+    uuid: uuid::Uuid,
+    /// What the pod's apps find their metadata with.
+    metadata_token: String,
+    metadata_url: String,
+    hostname: String,
+    app_dir: String,
+    /// Where the pod's `hosts`, `hostname` and `resolv.conf` are.
+    etc_dir: String,
+    namespaces: Option<&'a Namespaces>,
+    cgroup: Option<&'a Cgroup>,
+    root_switch: aci::RootSwitch,
+    /// Whether every app gets a read-only rootfs, whatever it asks for.
+    read_only_rootfs: bool
+}
 
-    acidirstr.push_str("rootfs/");
-    let shared_acidir = Arc::new(acidirstr);
-    manifest.mount_volumes(&vol_dir, &shared_acidir, volumes);
-    let (s, r) = channel();
-    Ok((s, thread::spawn({
-        let shared_acidir_clone = Arc::clone(&shared_acidir); 
-        move || {
-            r.recv().unwrap();
-            if let (Some(mut app_child), pre_start, post_stop) = manifest.exec(&shared_acidir_clone, pod_uuid) {
-                let run_app_child = if let Some(mut pre_start_cmd) = pre_start {
-                    if let Err(e) = pre_start_cmd.spawn().unwrap().wait() {
-                        println!("Error in pre-start: {}", e);
-                        false
-                    } else {
-                        true
+/// An app that is set up and only waits to be run by its thread.
+struct ReadyApp {
+    manifest: aci::Aci,
+    rootfs: String,
+    name: String,
+    namespaces: Option<namespace::Handle>,
+    sysctls: Sysctls,
+    cgroup: Option<Cgroup>,
+    exec_options: aci::ExecOptions
+}
+
+/// An app whose rootfs and volumes are mounted, waiting to be told whether
+/// to start. Either way its thread unmounts everything before finishing.
+struct AppThread {
+    name: String,
+    start: Sender<bool>,
+    handle: JoinHandle<Result<Option<ExitStatus>, Error>>
+}
+
+struct Options {
+    pod_manifest_path: Option<String>,
+    image_dir: String,
+    store_dir: String,
+    extract_options: store::ExtractOptions,
+    isolate: bool,
+    private_network: bool,
+    /// Whether the pod's network namespace is put on the default network.
+    default_network: bool,
+    bridge: String,
+    ip_range: String,
+    root_switch: aci::RootSwitch,
+    read_only_rootfs: bool,
+    resolv_conf: Option<String>,
+    cgroup_root: String,
+    name: Option<String>,
+    aci_paths: Vec<String>
+}
+
+fn parse_args() -> Result<Options, Error> {
+    let mut args = env::args().peekable();
+
+    // first argument is the name of the binary
+    args.next();
+
+    // `run` is optional so that `fyc foo.aci bar.aci` keeps working
+    if args.peek().map(|a| a.as_str()) == Some("run") {
+        args.next();
+    }
+
+    let mut options = Options {
+        pod_manifest_path: None,
+        image_dir: String::from(DEFAULT_IMAGE_DIR),
+        store_dir: String::from(store::DEFAULT_STORE_DIR),
+        extract_options: store::ExtractOptions::default(),
+        isolate: false,
+        private_network: false,
+        default_network: false,
+        bridge: String::from(network::DEFAULT_BRIDGE),
+        ip_range: String::from(network::DEFAULT_RANGE),
+        root_switch: aci::RootSwitch::Chroot,
+        read_only_rootfs: false,
+        resolv_conf: None,
+        cgroup_root: String::from(cgroup::DEFAULT_CGROUP_ROOT),
+        name: None,
+        aci_paths: Vec::new()
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pod-manifest" => options.pod_manifest_path = args.next(),
+            "--image-dir" => {
+                if let Some(d) = args.next() {
+                    options.image_dir = d;
+                }
+            }
+            "--store-dir" => {
+                if let Some(d) = args.next() {
+                    options.store_dir = d;
+                }
+            }
+            "--refuse-devices" => options.extract_options.refuse_devices = true,
+            "--refuse-setuid" => options.extract_options.refuse_setuid = true,
+            "--isolate" => options.isolate = true,
+            "--private-net" => {
+                options.isolate = true;
+                options.private_network = true;
+            }
+            "--net" => {
+                match args.next().as_deref() {
+                    Some("host") => {
+                        options.private_network = false;
+                        options.default_network = false;
+                    }
+                    Some("none") => {
+                        options.isolate = true;
+                        options.private_network = true;
+                        options.default_network = false;
+                    }
+                    Some("default") => {
+                        options.isolate = true;
+                        options.private_network = true;
+                        options.default_network = true;
                     }
-                } else {
-                    true
-                };
-                if run_app_child {
-                    app_child.spawn().unwrap().wait().unwrap();
+                    _ => return Err(Error::Usage(String::from(USAGE)))
                 }
-                if let Some(mut post_stop_cmd) = post_stop {
-                    post_stop_cmd.spawn().unwrap().wait().unwrap();
+            }
+            "--net-bridge" => {
+                if let Some(b) = args.next() {
+                    options.bridge = b;
+                }
+            }
+            "--net-range" => {
+                if let Some(r) = args.next() {
+                    options.ip_range = r;
+                }
+            }
+            "--name" => options.name = args.next(),
+            "--pivot-root" => options.root_switch = aci::RootSwitch::PivotRoot,
+            "--read-only-rootfs" => options.read_only_rootfs = true,
+            "--resolv-conf" => options.resolv_conf = args.next(),
+            "--cgroup-root" => {
+                if let Some(d) = args.next() {
+                    options.cgroup_root = d;
                 }
             }
-            manifest.unmount_volumes();
-    }})))
+            _ => options.aci_paths.push(arg)
+        }
+    }
+
+    if options.pod_manifest_path.is_some() && !options.aci_paths.is_empty() {
+        return Err(Error::Usage(String::from(USAGE)));
+    }
+    Ok(options)
 }
 
-fn main() {
-    let mut args = env::args();
-    let mut app_threads = Vec::new();
-    let mut handles = Vec::new();
+fn find_image(store: &store::Store, app: &pod::app::App) -> Result<String, Error> {
+    let id = app.get_image_id();
+    if !id.is_empty() {
+        return store.get_by_id(&id).map_err(Error::Image);
+    }
+    match app.get_image_name() {
+        Some(name) => store.get_by_name(&name, &app.get_image_labels())
+            .map_err(Error::Image),
+        None => Err(Error::Image(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("app {} has neither an image ID nor a name", app.get_name()))))
+    }
+}
 
-    // first argument is the name of the binary
-    args.next();
+fn wait_for(app_name: &str, mut cmd: process::Command) -> Result<ExitStatus, Error> {
+    cmd.spawn().and_then(|mut child| child.wait())
+        .map_err(|e| Error::Exec(String::from(app_name), e))
+}
+
+/// Runs an app along with its event handlers and returns how it exited, or
+/// how its pre-start handler did if that failed and kept the app from
+/// starting. The app's socket-activated ports are bound beforehand, in the
+/// pod's network.
+fn exec_app(app: &ReadyApp) -> Result<Option<ExitStatus>, Error> {
+    let sockets = aci::bind_sockets(&app.manifest.ports())?;
+    let (app_child, pre_start, post_stop) = match app.manifest.exec(&app.rootfs, &app.name,
+                                                                    &app.exec_options,
+                                                                    &sockets) {
+        (Some(a), pre_start, post_stop) => (a, pre_start, post_stop),
+        _ => return Ok(None)
+    };
+    let mut status = match pre_start {
+        Some(pre_start_cmd) => wait_for(&app.name, pre_start_cmd),
+        None => Ok(ExitStatus::default())
+    };
+    if let Ok(ref s) = status {
+        if s.success() {
+            status = wait_for(&app.name, app_child);
+        }
+    }
+    if let Some(post_stop_cmd) = post_stop {
+        let post_stopped = wait_for(&app.name, post_stop_cmd);
+        status = status.and_then(|s| post_stopped.map(|_| s));
+    }
+    status.map(Some)
+}
+
+/// Runs an app once the pod is ready, inside the pod's namespaces if it has
+/// any, then unmounts everything that was mounted for it and removes its
+/// cgroup. If the pod fails to start instead, only the cleaning up happens.
+fn run_app(app: ReadyApp,
+           start: Receiver<bool>) -> Result<Option<ExitStatus>, Error> {
+    let mut result = Ok(None);
+    if start.recv() == Ok(true) {
+        result = match app.namespaces {
+            Some(n) => n.enter().and_then(|_| app.sysctls.apply()),
+            None => Ok(())
+        }.and_then(|_| exec_app(&app));
+    }
+    let unmounted = app.manifest.unmount_volumes();
+    let removed = app.cgroup.map_or(Ok(()), |c| c.remove());
+    unmounted.and(removed).and(result)
+}
+
+/// Gives an app a cgroup of its own in the pod's, limited by its isolators.
+fn app_cgroup(pod_cgroup: &Cgroup, app_name: &str,
+              isolators: &[aci::Isolator]) -> Result<Cgroup, Error> {
+    let cgroup = pod_cgroup.create_child(app_name)?;
+    if let Err(e) = cgroup.set_limits(isolators) {
+        let _ = cgroup.remove();
+        return Err(e);
+    }
+    Ok(cgroup)
+}
+
+fn mount_app(manifest: &mut aci::Aci, layers: &[String], app_dir: &str,
+             mounts: &[aci::VolumeMount], pod: &PodContext,
+             read_only_rootfs: bool) -> Result<String, Error> {
+    let rootfs = manifest.mount_rootfs(layers, app_dir)?;
+    let proc_dir = pod.namespaces.map(|n| n.proc_dir());
+    manifest.mount_volumes(&rootfs, mounts, proc_dir, &pod.etc_dir, read_only_rootfs)?;
+    Ok(rootfs)
+}
+
+fn run_aci(store: &store::Store, image_id: &str, pod: &PodContext,
+           pod_app: Option<&pod::app::App>,
+           volumes: &mut PodVolumes) -> Result<(aci::AciJson, AppThread), Error> {
+    let image_manifest = store.manifest(image_id)
+        .map_err(|e| Error::Manifest(String::from(image_id), e))?;
+    let mut manifest : aci::Aci = aci::Aci::new(image_manifest.clone());
+
+    let (app_name, app_mounts, read_only_rootfs) = match pod_app {
+        Some(a) => (a.get_name(), a.get_mounts(),
+                    pod.read_only_rootfs || a.read_only_rootfs()),
+        None => (image_manifest.app_name(), Vec::new(), pod.read_only_rootfs)
+    };
+    let mounts = pod::resolve_mounts(&volumes.defined, &app_mounts,
+                                     &manifest.mount_points(), &volumes.dir,
+                                     &mut volumes.created)
+        .map_err(Error::Volume)?;
+    let layers = store.layers(image_id).map_err(Error::Image)?;
+
+    let isolators = manifest.isolators();
+    let capabilities = aci::Capabilities::from_isolators(&isolators)?;
+    let no_new_privileges = aci::no_new_privileges(&isolators)?;
+    let seccomp = aci::Seccomp::from_isolators(&isolators, no_new_privileges)?;
+    let oom_score_adj = aci::oom_score_adj(&isolators)?;
+    let sysctls = Sysctls::from_isolators(&isolators, pod.namespaces)?;
+    let cgroup = match pod.cgroup {
+        Some(c) => Some(app_cgroup(c, &app_name, &isolators)?),
+        None => {
+            cgroup::check_unneeded(&isolators)?;
+            None
+        }
+    };
+
+    let mut app_dir = pod.app_dir.clone();
+    app_dir.push_str(&app_name);
+    app_dir.push('/');
+    let mounted = create_dir(&app_dir)
+        .map_err(Error::from)
+        .and_then(|_| mount_app(&mut manifest, &layers, &app_dir, &mounts,
+                                pod, read_only_rootfs));
+    let identity = mounted.and_then(|r| match manifest.identity(&r) {
+        Ok(ids) => Ok((r, ids)),
+        Err(e) => Err(Error::Identity(app_name.clone(), e))
+    });
+    let (rootfs, (uid, gid)) = match identity {
+        Ok(r) => r,
+        Err(e) => {
+            let _ = manifest.unmount_volumes();
+            if let Some(c) = cgroup {
+                let _ = c.remove();
+            }
+            return Err(e);
+        }
+    };
+
+    let (s, r) = channel();
+    let exec_options = aci::ExecOptions {
+        metadata_url: pod.metadata_url.clone(),
+        root_switch: pod.root_switch,
+        uid,
+        gid,
+        cgroup_procs: cgroup.as_ref().map(|c| c.procs_file()),
+        capabilities,
+        no_new_privileges,
+        oom_score_adj,
+        seccomp
+    };
+    let app = ReadyApp {
+        manifest,
+        rootfs,
+        name: app_name.clone(),
+        namespaces: pod.namespaces.map(|n| n.handle()),
+        sysctls,
+        cgroup,
+        exec_options
+    };
+    let handle = thread::spawn(move || run_app(app, r));
+    Ok((image_manifest, AppThread {
+        name: app_name,
+        start: s,
+        handle
+    }))
+}
+
+fn read_pod_manifest(pstr: &str) -> Result<pod::Pod, Error> {
+    let mut manifest_str = String::new();
+    File::open(pstr)
+        .and_then(|mut f| f.read_to_string(&mut manifest_str))
+        .map_err(|e| Error::Manifest(String::from(pstr), e))?;
+    serde_json::from_str(&manifest_str)
+        .map_err(|e| Error::Manifest(String::from(pstr), e.into()))
+}
+
+/// Sets up every app of the pod described by `options`, adding their
+/// threads to `app_threads` as it goes so that they can be rolled back if a
+/// later one fails.
+fn set_up_pod(options: &Options, image_store: &store::Store, pod: &PodContext,
+              volumes: &mut PodVolumes,
+              app_threads: &mut Vec<AppThread>) -> Result<pod::Pod, Error> {
+    if let Some(ref manifest_path) = options.pod_manifest_path {
+        let mut app_pod = read_pod_manifest(manifest_path)?;
+        app_pod.set_uuid(pod.uuid);
+
+        let isolators = app_pod.isolators_or_empty();
+        match pod.cgroup {
+            Some(c) => c.set_limits(&isolators)?,
+            None => cgroup::check_unneeded(&isolators)?
+        }
+
+        volumes.defined = app_pod.volumes_or_empty();
+        let mut apps = app_pod.apps_or_empty();
+        let names : Vec<String> = apps.iter().map(|a| a.get_name()).collect();
+        pod::etc::write_files(&pod.etc_dir, &pod.hostname, &names,
+                              options.resolv_conf.as_deref())?;
+        for app in apps.iter_mut() {
+            let id = find_image(image_store, app)?;
+            let (image_manifest, app_thread) =
+                run_aci(image_store, &id, pod, Some(app), volumes)?;
+            app.set_image_id(id);
+            app.set_default_app(image_manifest);
+            app_threads.push(app_thread);
+        }
+        app_pod.set_apps(apps);
+        Ok(app_pod)
+    } else {
+        let mut ids = Vec::new();
+        let mut names = Vec::new();
+        for arg in &options.aci_paths {
+            let id = image_store.import(arg)
+                .map_err(|e| Error::Extract(arg.clone(), e))?;
+            let image_manifest = image_store.manifest(&id)
+                .map_err(|e| Error::Manifest(id.clone(), e))?;
+            names.push(image_manifest.app_name());
+            ids.push(id);
+        }
+        pod::etc::write_files(&pod.etc_dir, &pod.hostname, &names,
+                              options.resolv_conf.as_deref())?;
+
+        let mut apps = Vec::new();
+        for id in ids {
+            let (image_manifest, app_thread) =
+                run_aci(image_store, &id, pod, None, volumes)?;
+            apps.push(pod::app::App::new(image_manifest.app_name(), id,
+                                         image_manifest, Vec::new()));
+            app_threads.push(app_thread);
+        }
 
-    let mut volumes : HashSet<String> = HashSet::new();
+        Ok(pod::Pod::new(pod.uuid, POD_VERSION, Some(apps), volumes.created.clone()))
+    }
+}
 
-    let close_service = metadata::start(&*METADATA_STORE);
+/// Gives the pod its own namespaces and moves the calling thread into its
+/// mount namespace, so that everything mounted for the pod stays there.
+fn isolate(options: &Options, hostname: &str,
+           proc_dir: &str) -> Result<Namespaces, Error> {
+    create_dir(proc_dir)?;
+    let namespaces = Namespaces::create(hostname, proc_dir,
+                                        options.private_network)?;
+    namespaces.enter_mounts()?;
+    Ok(namespaces)
+}
+
+/// Undoes a pod that failed to start: every app that was already set up
+/// unmounts what it mounted, then the pod's namespaces and directory go
+/// away.
+fn roll_back(app_threads: Vec<AppThread>, namespaces: Option<Namespaces>,
+             cgroup: Option<Cgroup>, pod_dir: &str) {
+    let mut unmounted = true;
+    for app_thread in app_threads {
+        drop(app_thread.start);
+        if !matches!(app_thread.handle.join(), Ok(Ok(_))) {
+            unmounted = false;
+        }
+    }
+    drop(namespaces);
+    if let Some(c) = cgroup {
+        let _ = c.remove();
+    }
+    // With anything still mounted, removing the pod directory could reach
+    // into a host volume.
+    if unmounted {
+        let _ = remove_dir_all(pod_dir);
+    }
+}
+
+/// Runs the pod and returns the exit code fyc should finish with.
+fn run() -> Result<i32, Error> {
+    let options = parse_args()?;
+    let mut app_threads = Vec::new();
+
+    let image_store = store::Store::new(&options.store_dir,
+                                        Some(options.image_dir.clone()),
+                                        options.extract_options)
+        .map_err(|e| Error::Extract(options.store_dir.clone(), e))?;
+
+    let runtime = Runtime::new().map_err(Error::Metadata)?;
+    // Only one fyc at a time gets to serve metadata on a given address, so
+    // the others run their pods without it.
+    let close_service = match runtime.block_on(metadata::start(&METADATA_STORE)) {
+        Ok(s) => Some(s),
+        Err(e) => {
+            eprintln!("fyc: {}; apps get no metadata", e);
+            None
+        }
+    };
 
     let pod_uuid = uuid::Uuid::new_v4();
-    let pod_version = "0.8.9";
+    let metadata_token = metadata::new_token()?;
     // METADATA_STORE.write().unwrap().register_pod(format!("{{\"acKind\": \"PodManifest\", \"acVersion\":, \"uuid\": \"{}\", \"annotations\": []}}", pod_uuid));
 
     let mut pod_dir = String::from("/opt/fyc/");
     pod_dir.push_str(&pod_uuid.hyphenated().to_string());
     pod_dir.push('/');
-    if create_dir(pod_dir.clone()).is_err() {
-        println!("Error creating directory for Pod");
-        return;
-    }
+    create_dir(pod_dir.clone())?;
+
+    let pod_name = if options.isolate {
+        options.name.clone()
+            .unwrap_or_else(|| format!("fyc-{}", pod_uuid.hyphenated()))
+    } else {
+        namespace::hostname()?
+    };
+    let namespaces = if options.isolate {
+        let mut pod_proc_dir = pod_dir.clone();
+        pod_proc_dir.push_str(PROC_DIR);
+        match isolate(&options, &pod_name, &pod_proc_dir) {
+            Ok(n) => Some(n),
+            Err(e) => {
+                roll_back(Vec::new(), None, None, &pod_dir);
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+
+    let network = match namespaces {
+        Some(ref n) if options.default_network => {
+            match Network::create(&options.bridge, &options.ip_range, &pod_uuid, n) {
+                Ok(net) => Some(net),
+                Err(e) => {
+                    roll_back(Vec::new(), namespaces, None, &pod_dir);
+                    return Err(e);
+                }
+            }
+        }
+        _ => None
+    };
+    // The host's loopback is out of reach of pods on the default network,
+    // which get to the metadata service through the bridge.
+    let metadata_ip = match network {
+        Some(ref net) => {
+            if let Err(e) = runtime.block_on(metadata::listen(&METADATA_STORE, net.gateway())) {
+                eprintln!("fyc: {}; apps get no metadata", e);
+            }
+            net.gateway()
+        }
+        None => Ipv4Addr::LOCALHOST
+    };
+    let metadata_url = metadata::url(metadata_ip, &metadata_token);
+
+    let pod_cgroup = Cgroup::create_pod(&options.cgroup_root,
+                                        &pod_uuid.hyphenated().to_string());
 
     let mut pod_app_dir = pod_dir.clone();
     pod_app_dir.push_str(APP_DIR);
-    if create_dir(pod_app_dir.clone()).is_err() {
-        println!("Error creating apps directory for Pod");
-        return;
-    }
-
     let mut pod_vol_dir = pod_dir.clone();
     pod_vol_dir.push_str(VOL_DIR);
-    if create_dir(pod_vol_dir.clone()).is_err() {
-        println!("Error creating volumes directory for Pod");
-        return;
+    let mut pod_etc_dir = pod_dir.clone();
+    pod_etc_dir.push_str(ETC_DIR);
+
+    let mut volumes = PodVolumes {
+        defined: Vec::new(),
+        dir: pod_vol_dir.clone(),
+        created: HashSet::new()
+    };
+
+    let pod = PodContext {
+        uuid: pod_uuid,
+        metadata_token,
+        metadata_url,
+        hostname: pod_name,
+        app_dir: pod_app_dir.clone(),
+        etc_dir: pod_etc_dir,
+        namespaces: namespaces.as_ref(),
+        cgroup: pod_cgroup.as_ref(),
+        root_switch: options.root_switch,
+        read_only_rootfs: options.read_only_rootfs
+    };
+
+    let set_up = create_dir(&pod_app_dir)
+        .and_then(|_| create_dir(&pod_vol_dir))
+        .map_err(Error::from)
+        .and_then(|_| set_up_pod(&options, &image_store, &pod, &mut volumes,
+                                 &mut app_threads))
+        .and_then(|app_pod| portfwd::start(&runtime, &app_pod, namespaces.as_ref())
+                  .map(|_| app_pod))
+        .and_then(|mut app_pod| {
+            if let Some(ref net) = network {
+                app_pod.set_annotation(network::IP_ANNOTATION, net.ip().to_string());
+            }
+            METADATA_STORE.write().unwrap().register_pod(app_pod, pod.metadata_token.clone())
+        });
+    if let Err(e) = set_up {
+        roll_back(app_threads, namespaces, pod_cgroup, &pod_dir);
+        return Err(e);
     }
 
-    for arg in args {
-        let untar_str = untar(&arg, pod_app_dir.clone()).unwrap();
-        match run_aci(&mut volumes, pod_uuid.clone(), untar_str,
-                      pod_vol_dir.clone()) {
-            Ok(t) => app_threads.push(t),
-            Err(e) => {
-                println!("Error running a container: {}", e);
-                return;
+    for app_thread in &app_threads {
+        let _ = app_thread.start.send(true);
+    }
+
+    let mut result = Ok(());
+    let mut statuses = Vec::new();
+    for app_thread in app_threads {
+        let exit_status = match app_thread.handle.join() {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => {
+                result = result.and(Err(e));
+                None
             }
-        }
+            Err(_) => {
+                result = result.and(Err(Error::Exec(app_thread.name.clone(),
+                                                    io::Error::other("app thread panicked"))));
+                None
+            }
+        };
+        statuses.push(pod::status::AppStatus::new(app_thread.name, exit_status));
     }
 
-    let app_pod = pod::Pod::new(
-        pod_uuid.clone(), pod_version, Some(Vec::new()), volumes,
-        Some(Vec::new()), Some(Vec::new()), Some(Vec::new()),
-        Some(HashMap::new()), Some(HashMap::new())
-    );
+    let pod_status = pod::status::PodStatus::new(pod_uuid, statuses);
+    let mut status_path = pod_dir.clone();
+    status_path.push_str(STATUS_FILE);
+    let written = pod_status.write(&status_path);
 
-    METADATA_STORE.write().unwrap().register_pod(app_pod);
+    if let Some(c) = pod_cgroup {
+        result = result.and(c.remove());
+    }
 
-    for (s, h) in app_threads {
-        s.send(true).unwrap();
-        handles.push(h);
+    if let Some(s) = close_service {
+        let _ = s.send(true);
     }
+    result?;
+    written?;
+    Ok(pod_status.exit_code())
+}
 
-    for handle in handles {
-        if handle.join().is_err() {
-            println!("Oh no, error in a thread.")
+fn main() {
+    match run() {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("fyc: {}", e);
+            process::exit(1);
         }
     }
-
-    Handle::current().block_on(async move {
-        close_service.await.send(true).unwrap();
-    });
 }
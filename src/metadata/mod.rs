@@ -2,20 +2,25 @@ use hyper::{Response, Request};
 use hyper::StatusCode;
 use hyper::Method;
 use hyper::service::service_fn;
-use hyper::body::Incoming;
+use hyper::body::{Bytes, Incoming};
+use hyper::http::request::Parts;
 use hyper_util::server::conn::auto::Builder;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 
-use tokio::runtime::Handle;
+use http_body_util::BodyExt;
+
 use tokio::net::TcpListener;
 use tokio::task::JoinSet;
 
-use serde_json;
-
 use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::Ipv4Addr;
 use std::sync::RwLock;
 use std::sync::mpsc::{channel, Sender};
 
+use crate::error::Error;
 use crate::pod::Pod;
 
 mod app;
@@ -23,19 +28,42 @@ mod pod;
 
 use self::pod::PodMetadata;
 
-pub const HOST_PORT: &'static str = "127.0.0.1:2377";
+const PORT: u16 = 2377;
+const TOKEN_LEN: usize = 32;
 
 pub struct Metadata {
+    /// Pods by the token in their apps' metadata URL.
     pod_map: HashMap<String, PodMetadata>
 }
 
-pub async fn start(md: &'static RwLock<Metadata>) -> Sender<bool> {
+/// A random token for a pod's apps to reach its metadata with. Unlike the
+/// pod's UUID, which any app may learn, only the pod's own apps are given it.
+pub fn new_token() -> Result<String, Error> {
+    let mut bytes = [0u8; TOKEN_LEN];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map_err(Error::Metadata)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The address of the metadata service's URL for apps on a network with
+/// the host at `ip`, given the pod's `token`.
+pub fn url(ip: Ipv4Addr, token: &str) -> String {
+    format!("http://{}:{}/{}", ip, PORT, token)
+}
+
+pub async fn start(md: &'static RwLock<Metadata>) -> Result<Sender<bool>, Error> {
     let (s, _r) = channel();
+    listen(md, Ipv4Addr::LOCALHOST).await?;
+    Ok(s)
+}
+
+/// Serves `md` on the host's address `ip` as well, for pods whose network
+/// reaches the host there.
+pub async fn listen(md: &'static RwLock<Metadata>, ip: Ipv4Addr) -> Result<(), Error> {
+    let tcp_listener = TcpListener::bind((ip, PORT)).await
+        .map_err(|e| Error::Metadata(io::Error::new(e.kind(), format!("{}:{}: {}", ip, PORT, e))))?;
     tokio::spawn(async move {
-        let tcp_listener = match TcpListener::bind(HOST_PORT).await {
-            Ok(r) => r,
-            Err(_e) => { return; }
-        };
         let mut join_set = JoinSet::new();
         loop {
             let (stream, _addr) = match tcp_listener.accept().await {
@@ -48,10 +76,15 @@ pub async fn start(md: &'static RwLock<Metadata>) -> Sender<bool> {
             let serve_connection = async move {
                 let _r = Builder::new(TokioExecutor::new())
                     .serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                        let (parts, body) = req.into_parts();
+                        let body = match body.collect().await {
+                            Ok(b) => b.to_bytes(),
+                            Err(_e) => Bytes::new()
+                        };
                         let metadata = md.read().unwrap();
-                        let res = metadata.handle(req);
+                        let res = metadata.handle(parts, body);
                         Ok::<Response<String>, String>(res)
-                    }));
+                    })).await;
             };
             
             join_set.spawn(serve_connection);
@@ -64,7 +97,7 @@ pub async fn start(md: &'static RwLock<Metadata>) -> Sender<bool> {
         // r.recv().unwrap();
         // listener.close().unwrap();
     });
-    s
+    Ok(())
 }
 
 impl Metadata {
@@ -74,13 +107,12 @@ impl Metadata {
         }
     }
 
-    fn handle(&self, req: Request<Incoming>) -> Response<String> {
+    fn handle(&self, req: Parts, body: Bytes) -> Response<String> {
         let mut res: Response<String> = Default::default();
-        let path_str = req.uri().path();
-        let handle = Handle::current();
+        let path_str = req.uri.path();
 
-        let mut req_path_segs = if path_str.starts_with('/') {
-            path_str[1..].split('/')
+        let mut req_path_segs = if let Some(p) = path_str.strip_prefix('/') {
+            p.split('/')
         } else {
             *res.status_mut() = StatusCode::BAD_REQUEST;
             return res;
@@ -103,7 +135,7 @@ impl Metadata {
             return res;
         }
 
-        match *req.method() {
+        match req.method {
             Method::POST => {
                 if req_path_segs.next() != Some("pod") {
                     *res.status_mut() = StatusCode::NOT_FOUND;
@@ -114,8 +146,8 @@ impl Metadata {
                     return res;
                 }
                 match req_path_segs.next() {
-                    Some("sign") => handle.block_on(async move { pmd.sign(req, res).await }),
-                    Some("verify") => handle.block_on(async move { pmd.verify(req, res).await }),
+                    Some("sign") => pmd.sign(&body, res),
+                    Some("verify") => self.verify(&body, res),
                     _ => {
                         *res.status_mut() = StatusCode::NOT_FOUND; res
                     }
@@ -162,13 +194,13 @@ impl Metadata {
                             }
                             _ => {
                                 *res.status_mut() = StatusCode::NOT_FOUND;
-                                return res;
+                                res
                             }
                         }
                     }
                     _ => {
                         *res.status_mut() = StatusCode::NOT_FOUND;
-                        return res;
+                        res
                     }
                 }
             },
@@ -176,10 +208,38 @@ impl Metadata {
         }
     }
 
-    pub fn register_pod(&mut self, pod: Pod) {
-        if let Some(pod_metadata) = PodMetadata::new(pod) {
-            self.pod_map.insert(pod_metadata.uuid.clone(), pod_metadata);
-        }
+    pub fn register_pod(&mut self, pod: Pod, token: String) -> Result<(), Error> {
+        let pod_metadata = PodMetadata::new(pod)?;
+        self.pod_map.insert(token, pod_metadata);
+        Ok(())
+    }
+
+    fn get_by_uuid(&self, uuid: &str) -> Option<&PodMetadata> {
+        self.pod_map.values().find(|p| p.uuid == uuid)
+    }
+
+    fn verify(&self, body: &[u8], mut res: Response<String>) -> Response<String> {
+        let (content, signature, uuid) = match (form_value(body, "content"),
+                                                form_value(body, "signature"),
+                                                form_value(body, "uuid")) {
+            (Some(c), Some(s), Some(u)) => (c, s, u),
+            _ => {
+                *res.status_mut() = StatusCode::BAD_REQUEST;
+                return res;
+            }
+        };
+
+        let verified = match self.get_by_uuid(&uuid) {
+            Some(pmd) => pmd.verify(&content, &signature),
+            None => false
+        };
+
+        *res.status_mut() = if verified {
+            StatusCode::OK
+        } else {
+            StatusCode::FORBIDDEN
+        };
+        res
     }
 
     fn get_by_token(&self, token: Option<&str>) -> Option<&PodMetadata> {
@@ -192,7 +252,7 @@ impl Metadata {
 
     #[allow(dead_code)]
     pub fn get_pod(&self, uuid: String) -> String {
-        if let Some(pmd) = self.pod_map.get(&uuid) {
+        if let Some(pmd) = self.get_by_uuid(&uuid) {
             if let Ok(s) = serde_json::to_string(pmd) {
                 return s;
             }
@@ -202,7 +262,7 @@ impl Metadata {
 
     #[allow(dead_code)]
     fn get_app(&self, uuid: String, app_name: String) -> String {
-        if let Some(pmd) = self.pod_map.get(&uuid) {
+        if let Some(pmd) = self.get_by_uuid(&uuid) {
             if let Some(amd) = pmd.apps.get(&app_name) {
                 if let Ok(s) = serde_json::to_string(amd) {
                     return s;
@@ -212,3 +272,12 @@ impl Metadata {
         String::new()
     }
 }
+
+fn form_value(body: &[u8], key: &str) -> Option<String> {
+    for (k, v) in form_urlencoded::parse(body) {
+        if k == key {
+            return Some(v.into_owned());
+        }
+    }
+    None
+}
@@ -1,34 +1,47 @@
-use hyper::{Response, Request};
+use hyper::Response;
 use hyper::StatusCode;
 use hyper::header::CONTENT_TYPE;
 use hyper::header::HeaderValue;
-use hyper::body::{Incoming, Bytes};
 
-use http_body_util::BodyExt;
-use futures_util::StreamExt;
-use futures_util::FutureExt;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha512;
 
-use std::convert::Infallible;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
 
-use serde_json;
-
+use crate::error::Error;
 use crate::pod::Pod;
 use crate::util::NameValue;
 
 use super::app::AppMetadata;
+use super::form_value;
+
+type HmacSha512 = Hmac<Sha512>;
+
+const HMAC_KEY_LEN: usize = 64;
 
 #[derive(Serialize)]
 pub struct PodMetadata {
     annotations: Vec<NameValue>,
     pub apps: HashMap<String, AppMetadata>,
-    // hmac: ???
+    #[serde(skip)]
+    hmac_key: Vec<u8>,
     manifest: String,
     pub uuid: String
 }
 
+fn new_hmac_key() -> io::Result<Vec<u8>> {
+    let mut key = vec![0; HMAC_KEY_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut key)?;
+    Ok(key)
+}
+
 impl PodMetadata {
-    pub fn new(pod: Pod) -> Option<PodMetadata> {
+    pub fn new(pod: Pod) -> Result<PodMetadata, Error> {
         let annotations = pod.annotations_or_empty();
         let pod_apps = pod.apps_or_empty();
         let mut apps : HashMap<String, AppMetadata> = HashMap::new();
@@ -38,15 +51,14 @@ impl PodMetadata {
                                                        a.get_image_id()));
         }
 
-        let manifest_json = if let Ok(j) = serde_json::to_string(&pod) {
-            j
-        } else {
-            String::from("")
-        };
+        let manifest_json = serde_json::to_string(&pod).unwrap_or_default();
 
-        Some(PodMetadata {
-            annotations: annotations,
-            apps: apps,
+        let hmac_key = new_hmac_key().map_err(Error::Metadata)?;
+
+        Ok(PodMetadata {
+            annotations,
+            apps,
+            hmac_key,
             manifest: manifest_json,
             uuid: pod.get_uuid()
         })
@@ -59,65 +71,63 @@ impl PodMetadata {
         None
     }
 
-    pub async fn sign(&self, mut req: Request<Incoming>, mut res: Response<String>) -> Response<String> {
-        let req_body: &mut Incoming = req.body_mut();
-        let mut req_stream = req_body.frame().map(|f| Ok::<Bytes, Infallible>(f.unwrap().unwrap().into_data().unwrap())).into_stream();
-        let mut req_bodystr = String::new();
-        while let Some(req_data) = req_stream.next().await {
-            req_bodystr.push_str(&String::from_utf8(req_data.unwrap().to_vec()).unwrap());
-        }
-        *res.status_mut() = StatusCode::OK;
-        let ref mut res_headers = res.headers_mut();
-        res_headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=us-ascii"));
-        let ref mut res_body = res.body_mut();
-        *res_body = &mut req_bodystr;
-        res
+    fn new_mac(&self) -> HmacSha512 {
+        HmacSha512::new_from_slice(&self.hmac_key).unwrap()
     }
 
-    pub async fn verify(&self, mut req: Request<Incoming>, mut res: Response<String>) -> Response<String> {
-        let req_body: &mut Incoming = req.body_mut();
-        let mut req_stream = req_body.frame().map(|f| Ok::<Bytes, Infallible>(f.unwrap().unwrap().into_data().unwrap())).into_stream();
-        let mut req_bodystr = String::new();
-        while let Some(req_data) = req_stream.next().await {
-            req_bodystr.push_str(&String::from_utf8(req_data.unwrap().to_vec()).unwrap());
-        }
+    pub fn sign(&self, body: &[u8], mut res: Response<String>) -> Response<String> {
+        let content = if let Some(c) = form_value(body, "content") {
+            c
+        } else {
+            *res.status_mut() = StatusCode::BAD_REQUEST;
+            return res;
+        };
+
+        let mut mac = self.new_mac();
+        mac.update(content.as_bytes());
+        let signature = BASE64.encode(mac.finalize().into_bytes());
 
         *res.status_mut() = StatusCode::OK;
-        let ref mut res_headers = res.headers_mut();
-        res_headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=us-ascii"));
-        let ref mut res_body = res.body_mut();
-        *res_body = &mut req_bodystr;
+        res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=us-ascii"));
+        *res.body_mut() = signature;
         res
     }
 
+    pub fn verify(&self, content: &str, signature: &str) -> bool {
+        let sig_bytes = match BASE64.decode(signature) {
+            Ok(b) => b,
+            Err(_) => return false
+        };
+        let mut mac = self.new_mac();
+        mac.update(content.as_bytes());
+        mac.verify_slice(&sig_bytes).is_ok()
+    }
+
     pub fn serve_annotations(&self, mut res: Response<String>) -> Response<String> {
         *res.status_mut() = StatusCode::OK;
-        let ref mut res_headers = res.headers_mut();
+        let res_headers = res.headers_mut();
         res_headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
         let send_json = if let Ok(j) = serde_json::to_string(&self.annotations) {
             j
         } else {
             String::from("null")
         };
-        let ref mut res_body = res.body_mut();
-        *res_body = &mut send_json.clone();
+        *res.body_mut() = send_json;
         res
     }
 
     pub fn serve_manifest(&self, mut res: Response<String>) -> Response<String> {
         *res.status_mut() = StatusCode::OK;
-        let ref mut res_headers = res.headers_mut();
+        let res_headers = res.headers_mut();
         res_headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        let ref mut res_body = res.body_mut();
-        *res_body = &mut self.manifest.clone();
+        *res.body_mut() = self.manifest.clone();
         res
     }
 
     pub fn serve_uuid(&self, mut res: Response<String>) -> Response<String> {
         *res.status_mut() = StatusCode::OK;
         res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=us-ascii"));
-        let ref mut res_body = res.body_mut();
-        *res_body = &mut self.uuid.clone();
+        *res.body_mut() = self.uuid.clone();
         res
     }
 
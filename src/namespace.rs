@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{write, File};
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+
+use crate::aci::Isolator;
+use crate::error::Error;
+
+const INIT_STACK_SIZE : usize = 64 * 1024;
+const SYSCTL_ISOLATOR : &str = "os/unix/sysctl";
+/// The sysctls that belong to an IPC namespace rather than the host.
+const IPC_SYSCTLS : [&str; 9] = [
+    "kernel.msgmax", "kernel.msgmnb", "kernel.msgmni", "kernel.sem",
+    "kernel.shmall", "kernel.shmmax", "kernel.shmmni", "kernel.shm_rmid_forced",
+    "fs.mqueue."
+];
+
+/// What the pod's init process needs to set its namespaces up. It is only
+/// read after `clone`, where nothing may be allocated any more.
+struct InitArgs {
+    hostname: CString,
+    proc_dir: CString,
+    network: bool,
+    ready: libc::c_int
+}
+
+/// A pod's namespaces, held by an init process that sits in all of them and
+/// reaps whatever the apps leave behind. Dropping it kills everything still
+/// running in the pod.
+pub struct Namespaces {
+    init: libc::pid_t,
+    network: bool,
+    proc_dir: String
+}
+
+/// Lets an app thread start its processes inside a pod's namespaces.
+#[derive(Clone, Copy)]
+pub struct Handle {
+    init: libc::pid_t,
+    network: bool
+}
+
+/// Kernel parameters set inside a pod's namespaces for an app, as asked by
+/// its `os/unix/sysctl` isolator.
+pub struct Sysctls {
+    values: Vec<(String, String)>
+}
+
+fn last_errno() -> libc::c_int {
+    io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO)
+}
+
+unsafe fn bring_up_loopback() -> Result<(), libc::c_int> {
+    let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+    if sock < 0 {
+        return Err(last_errno());
+    }
+    let mut req : libc::ifreq = mem::zeroed();
+    for (i, c) in b"lo".iter().enumerate() {
+        req.ifr_name[i] = *c as libc::c_char;
+    }
+    let mut result = Ok(());
+    if libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req) != 0 {
+        result = Err(last_errno());
+    } else {
+        req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        if libc::ioctl(sock, libc::SIOCSIFFLAGS, &mut req) != 0 {
+            result = Err(last_errno());
+        }
+    }
+    libc::close(sock);
+    result
+}
+
+unsafe fn set_up(args: &InitArgs) -> Result<(), libc::c_int> {
+    if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+        return Err(last_errno());
+    }
+    // Nothing mounted in the pod may show up on the host.
+    if libc::mount(ptr::null(), c"/".as_ptr(), ptr::null(),
+                   libc::MS_REC | libc::MS_PRIVATE, ptr::null()) != 0 {
+        return Err(last_errno());
+    }
+    if libc::sethostname(args.hostname.as_ptr(),
+                         args.hostname.as_bytes().len()) != 0 {
+        return Err(last_errno());
+    }
+    let proc_type = c"proc".as_ptr();
+    if libc::mount(proc_type, args.proc_dir.as_ptr(), proc_type,
+                   libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                   ptr::null()) != 0 {
+        return Err(last_errno());
+    }
+    if args.network {
+        bring_up_loopback()?;
+    }
+    Ok(())
+}
+
+/// Waits for children forever. Being PID 1 of the pod, the init process
+/// inherits every orphan in it.
+unsafe fn reap() -> ! {
+    let mut signals : libc::sigset_t = mem::zeroed();
+    libc::sigemptyset(&mut signals);
+    libc::sigaddset(&mut signals, libc::SIGCHLD);
+    loop {
+        libc::sigwaitinfo(&signals, ptr::null_mut());
+        while libc::waitpid(-1, ptr::null_mut(), libc::WNOHANG) > 0 {}
+    }
+}
+
+extern "C" fn init_main(arg: *mut libc::c_void) -> libc::c_int {
+    unsafe {
+        let args = &*(arg as *const InitArgs);
+        let mut signals : libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGCHLD);
+        libc::sigprocmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+
+        let status = match set_up(args) {
+            Ok(()) => 0,
+            Err(e) => e
+        };
+        libc::write(args.ready, &status as *const libc::c_int as *const libc::c_void,
+                    mem::size_of::<libc::c_int>());
+        libc::close(args.ready);
+        if status != 0 {
+            libc::_exit(1);
+        }
+        reap()
+    }
+}
+
+/// The hostname of the calling thread's UTS namespace.
+pub fn hostname() -> Result<String, Error> {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return Err(Error::Namespace(io::Error::last_os_error()));
+    }
+    let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    Ok(String::from_utf8_lossy(&name[..len]).into_owned())
+}
+
+fn enter(init: libc::pid_t, kind: &str, nstype: libc::c_int) -> Result<(), Error> {
+    let ns = File::open(format!("/proc/{}/ns/{}", init, kind))
+        .map_err(Error::Namespace)?;
+    if unsafe { libc::setns(ns.as_raw_fd(), nstype) } != 0 {
+        return Err(Error::Namespace(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+impl Namespaces {
+    /// Starts the init process of a pod with its own mount, PID, UTS and IPC
+    /// namespaces, plus a network namespace with only a loopback interface
+    /// if `network` is set. The pod gets `hostname` and a procfs showing
+    /// only its own processes is mounted on `proc_dir`.
+    pub fn create(hostname: &str, proc_dir: &str,
+                  network: bool) -> Result<Namespaces, Error> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(Error::Namespace(io::Error::last_os_error()));
+        }
+        let mut ready = unsafe { File::from_raw_fd(fds[0]) };
+        let args = InitArgs {
+            hostname: CString::new(hostname).unwrap(),
+            proc_dir: CString::new(proc_dir).unwrap(),
+            network,
+            ready: fds[1]
+        };
+
+        let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWUTS |
+            libc::CLONE_NEWIPC | libc::SIGCHLD;
+        if network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        let mut stack = vec![0u8; INIT_STACK_SIZE];
+        let stack_top = (stack.as_mut_ptr() as usize + INIT_STACK_SIZE) & !15;
+        let init = unsafe {
+            libc::clone(init_main, stack_top as *mut libc::c_void, flags,
+                        &args as *const InitArgs as *mut libc::c_void)
+        };
+        let clone_error = io::Error::last_os_error();
+        unsafe { libc::close(fds[1]); }
+        if init < 0 {
+            return Err(Error::Namespace(clone_error));
+        }
+
+        let namespaces = Namespaces {
+            init,
+            network,
+            proc_dir: String::from(proc_dir)
+        };
+        let mut status = [0u8; 4];
+        ready.read_exact(&mut status).map_err(Error::Namespace)?;
+        match libc::c_int::from_ne_bytes(status) {
+            0 => Ok(namespaces),
+            e => Err(Error::Namespace(io::Error::from_raw_os_error(e)))
+        }
+    }
+
+    /// Moves the calling thread into the pod's mount namespace, so that what
+    /// it and the threads it starts from now on mount stays inside the pod.
+    /// The working directory is kept, so that relative paths given on the
+    /// command line still work.
+    pub fn enter_mounts(&self) -> Result<(), Error> {
+        let cwd = File::open(".").map_err(Error::Namespace)?;
+        if unsafe { libc::unshare(libc::CLONE_FS) } != 0 {
+            return Err(Error::Namespace(io::Error::last_os_error()));
+        }
+        enter(self.init, "mnt", libc::CLONE_NEWNS)?;
+        if unsafe { libc::fchdir(cwd.as_raw_fd()) } != 0 {
+            return Err(Error::Namespace(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Whether the pod has a network namespace of its own.
+    pub fn private_network(&self) -> bool {
+        self.network
+    }
+
+    /// The pid of the pod's init process, as seen from the host.
+    pub fn pid(&self) -> libc::pid_t {
+        self.init
+    }
+
+    pub fn proc_dir(&self) -> &str {
+        &self.proc_dir
+    }
+
+    pub fn handle(&self) -> Handle {
+        Handle {
+            init: self.init,
+            network: self.network
+        }
+    }
+}
+
+impl Drop for Namespaces {
+    fn drop(&mut self) {
+        let proc_dir = CString::new(self.proc_dir.clone()).unwrap();
+        unsafe {
+            libc::umount2(proc_dir.as_ptr(), libc::MNT_DETACH);
+            // Killing the init of a PID namespace kills everything in it.
+            libc::kill(self.init, libc::SIGKILL);
+            libc::waitpid(self.init, ptr::null_mut(), 0);
+        }
+    }
+}
+
+impl Handle {
+    /// Moves the calling thread into the pod's UTS, IPC and network
+    /// namespaces and makes the processes it starts from now on part of the
+    /// pod's PID namespace. The thread can't start threads of its own after
+    /// this.
+    pub fn enter(&self) -> Result<(), Error> {
+        enter(self.init, "pid", libc::CLONE_NEWPID)?;
+        enter(self.init, "uts", libc::CLONE_NEWUTS)?;
+        enter(self.init, "ipc", libc::CLONE_NEWIPC)?;
+        if self.network {
+            enter(self.init, "net", libc::CLONE_NEWNET)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the calling thread into the pod's network namespace only, so
+    /// that the sockets it makes are the pod's.
+    pub fn enter_network(&self) -> Result<(), Error> {
+        enter(self.init, "net", libc::CLONE_NEWNET)
+    }
+}
+
+impl Sysctls {
+    /// Gathers the sysctls of `isolators`, which may only be those of the
+    /// pod's own IPC namespace, or network namespace if it has a private
+    /// network. There are none to set outside of `namespaces`.
+    pub fn from_isolators(isolators: &[Isolator],
+                          namespaces: Option<&Namespaces>) -> Result<Sysctls, Error> {
+        let mut values = Vec::new();
+        for isolator in isolators {
+            if isolator.name != SYSCTL_ISOLATOR {
+                continue;
+            }
+            let invalid = |why: String| Error::Isolator(
+                isolator.name.clone(), io::Error::new(io::ErrorKind::InvalidInput, why));
+            let sysctls : BTreeMap<String, String> =
+                serde_json::from_value(isolator.value.clone())
+                .map_err(|e| Error::Isolator(isolator.name.clone(), e.into()))?;
+            for (name, value) in sysctls {
+                let ipc = IPC_SYSCTLS.iter()
+                    .any(|s| name == *s || (s.ends_with('.') && name.starts_with(s)));
+                let network = name.starts_with("net.");
+                if !ipc && !network {
+                    return Err(invalid(format!("{} isn't namespaced", name)));
+                }
+                match namespaces {
+                    None => return Err(invalid(format!(
+                        "{} needs the pod's own namespaces", name))),
+                    Some(n) if network && !n.network => return Err(invalid(format!(
+                        "{} needs a private network", name))),
+                    _ => {}
+                }
+                if name.contains('/') || name.contains("..") {
+                    return Err(invalid(format!("{} isn't a sysctl", name)));
+                }
+                values.push((name, value));
+            }
+        }
+        Ok(Sysctls {
+            values
+        })
+    }
+
+    /// Sets the sysctls in the namespaces of the calling thread, which must
+    /// have entered the pod's.
+    pub fn apply(&self) -> Result<(), Error> {
+        for (name, value) in &self.values {
+            write(format!("/proc/sys/{}", name.replace('.', "/")), value)
+                .map_err(|e| Error::Isolator(
+                    format!("{} {}", SYSCTL_ISOLATOR, name), e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use std::fs::{create_dir_all, metadata, remove_file, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+/// A range of addresses given as `address/prefix-length`.
+#[derive(Clone, Copy)]
+pub struct Range {
+    network: u32,
+    prefix_len: u8
+}
+
+/// An address leased to a pod, recorded by a file of its own in the
+/// directory of the range's leases, which holds the pod's UUID. The file is
+/// locked for as long as the lease is held, so that the lease of a fyc that
+/// was killed is free again. Dropping it gives the address back.
+pub struct Lease {
+    path: String,
+    ip: Ipv4Addr,
+    file: File
+}
+
+impl Range {
+    pub fn parse(range: &str) -> io::Result<Range> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput,
+                                        format!("bad address range {}", range));
+        let (address, prefix_len) = range.split_once('/').ok_or_else(invalid)?;
+        let address : Ipv4Addr = address.parse().map_err(|_| invalid())?;
+        let prefix_len : u8 = prefix_len.parse().map_err(|_| invalid())?;
+        // A range needs room for a gateway and at least one pod.
+        if !(8..=30).contains(&prefix_len) {
+            return Err(invalid());
+        }
+        Ok(Range {
+            network: u32::from(address) & (u32::MAX << (32 - prefix_len)),
+            prefix_len
+        })
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// The first address of the range, which the host keeps.
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.network + 1)
+    }
+
+    /// The addresses pods may get: all but the network, gateway and
+    /// broadcast addresses.
+    fn pod_addresses(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let broadcast = self.network | (u32::MAX >> self.prefix_len);
+        (self.network + 2..broadcast).map(Ipv4Addr::from)
+    }
+}
+
+/// Locks the lease file at `path` if no one holds it, making it if need be.
+/// Returns `None` if it is taken.
+fn lock(path: &str) -> io::Result<Option<File>> {
+    loop {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Ok(None),
+                _ => Err(e)
+            };
+        }
+        // A lease given back between opening and locking leaves this file
+        // unlinked, and another may already be in its place.
+        match metadata(path) {
+            Ok(md) if md.ino() == file.metadata()?.ino() => return Ok(Some(file)),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Leases the first free address of `range` to the pod `pod_uuid`, keeping
+/// track of leases in `dir`. Several fyc processes may lease from the same
+/// directory at once.
+pub fn lease(dir: &str, range: &Range, pod_uuid: &str) -> io::Result<Lease> {
+    create_dir_all(dir)?;
+    for ip in range.pod_addresses() {
+        let path = format!("{}{}", dir, ip);
+        let file = match lock(&path)? {
+            Some(f) => f,
+            None => continue
+        };
+        let mut lease = Lease {
+            path,
+            ip,
+            file
+        };
+        lease.file.set_len(0)?;
+        lease.file.write_all(pod_uuid.as_bytes())?;
+        return Ok(lease);
+    }
+    Err(io::Error::new(io::ErrorKind::AddrNotAvailable,
+                       format!("no address left in {}", dir)))
+}
+
+impl Lease {
+    pub fn ip(&self) -> Ipv4Addr {
+        self.ip
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // Removed while still locked, the lock going with the file.
+        let _ = remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{read_to_string, remove_dir_all, write};
+    use std::path::Path;
+
+    use crate::util::test_dir;
+
+    #[test]
+    fn ranges_parse() {
+        let range = Range::parse("10.200.3.4/16").unwrap();
+        assert_eq!(range.prefix_len(), 16);
+        assert_eq!(range.gateway(), Ipv4Addr::new(10, 200, 0, 1));
+        let range = Range::parse("192.168.1.0/30").unwrap();
+        let addresses : Vec<Ipv4Addr> = range.pod_addresses().collect();
+        assert_eq!(addresses, vec![Ipv4Addr::new(192, 168, 1, 2)]);
+        for bad in &["10.0.0.0", "10.0.0.0/31", "10.0.0.0/7", "10.0.0/16", "10.0.0.0/x"] {
+            assert_eq!(Range::parse(bad).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn leases_are_handed_out_once() {
+        let dir = format!("{}/", test_dir("ipam").display());
+        let range = Range::parse("10.0.0.0/29").unwrap();
+        let first = lease(&dir, &range, "a").unwrap();
+        let second = lease(&dir, &range, "b").unwrap();
+        assert_eq!(first.ip(), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(second.ip(), Ipv4Addr::new(10, 0, 0, 3));
+        assert_eq!(read_to_string(format!("{}10.0.0.2", dir)).unwrap(), "a");
+
+        drop(first);
+        assert!(!Path::new(&format!("{}10.0.0.2", dir)).exists());
+        assert_eq!(lease(&dir, &range, "c").unwrap().ip(), Ipv4Addr::new(10, 0, 0, 2));
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leases_of_killed_pods_are_free() {
+        let dir = format!("{}/", test_dir("ipam-stale").display());
+        let range = Range::parse("10.0.0.0/30").unwrap();
+        // What a fyc that was killed leaves behind: a file no one locks.
+        write(format!("{}10.0.0.2", dir), "gone").unwrap();
+        let taken = lease(&dir, &range, "new").unwrap();
+        assert_eq!(taken.ip(), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(read_to_string(format!("{}10.0.0.2", dir)).unwrap(), "new");
+        assert_eq!(lease(&dir, &range, "other").err().unwrap().kind(),
+                   io::ErrorKind::AddrNotAvailable);
+        drop(taken);
+        remove_dir_all(&dir).unwrap();
+    }
+}
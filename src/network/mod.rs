@@ -0,0 +1,119 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::thread;
+
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::namespace::{Handle, Namespaces};
+
+mod ipam;
+mod netlink;
+
+use self::netlink::Netlink;
+
+pub const DEFAULT_BRIDGE : &str = "fyc0";
+pub const DEFAULT_RANGE : &str = "10.200.0.0/16";
+/// The pod annotation the metadata service gives the pod's address in.
+pub const IP_ANNOTATION : &str = "fyc/ip-address";
+const IPAM_DIR : &str = "/var/lib/fyc/ipam/";
+const POD_LINK : &str = "eth0";
+
+/// A pod's place on the default network: a veth pair with one end in the
+/// pod and the other on a host bridge, and an address leased from the
+/// bridge's range. Dropping it removes the pair and gives the address back.
+pub struct Network {
+    lease: ipam::Lease,
+    gateway: Ipv4Addr,
+    host_link: String
+}
+
+/// Opens a netlink socket in the pod's network namespace, from a thread of
+/// its own since entering the namespace is for good.
+fn pod_netlink(handle: Handle) -> Result<Netlink, Error> {
+    thread::spawn(move || {
+        handle.enter_network()?;
+        Netlink::open().map_err(Error::Network)
+    }).join().unwrap_or_else(|_| Err(Error::Network(io::Error::other(
+        "could not enter the pod's network"))))
+}
+
+/// Makes sure `bridge` exists, is up and holds the gateway address of
+/// `range`, and returns its index.
+fn set_up_bridge(host: &mut Netlink, bridge: &str,
+                 range: &ipam::Range) -> io::Result<i32> {
+    let index = match host.link_index(bridge) {
+        Err(ref e) if e.raw_os_error() == Some(libc::ENODEV) => {
+            match host.create_bridge(bridge) {
+                Err(ref e) if e.raw_os_error() == Some(libc::EEXIST) => {},
+                r => r?
+            }
+            host.link_index(bridge)?
+        }
+        r => r?
+    };
+    match host.add_address(index, range.gateway(), range.prefix_len()) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EEXIST) => {},
+        r => r?
+    }
+    host.set_up(index, None)?;
+    Ok(index)
+}
+
+impl Network {
+    /// Puts the pod of `pod_uuid`, whose network namespace `namespaces`
+    /// holds, on `bridge` with an address from `range`. The bridge is
+    /// created if need be and the pod routes everything through it.
+    pub fn create(bridge: &str, range: &str, pod_uuid: &Uuid,
+                  namespaces: &Namespaces) -> Result<Network, Error> {
+        let range = ipam::Range::parse(range).map_err(Error::Network)?;
+        let mut host = Netlink::open().map_err(Error::Network)?;
+        let bridge_index = set_up_bridge(&mut host, bridge, &range)
+            .map_err(|e| Error::Network(io::Error::new(
+                e.kind(), format!("bridge {}: {}", bridge, e))))?;
+
+        let lease = ipam::lease(&format!("{}{}/", IPAM_DIR, bridge), &range,
+                                &pod_uuid.hyphenated().to_string())
+            .map_err(Error::Network)?;
+        let host_link = format!("veth{}", &pod_uuid.simple().to_string()[..8]);
+        host.create_veth(&host_link, POD_LINK, namespaces.pid()).map_err(Error::Network)?;
+        let network = Network {
+            lease,
+            gateway: range.gateway(),
+            host_link
+        };
+
+        let host_index = host.link_index(&network.host_link).map_err(Error::Network)?;
+        host.set_up(host_index, Some(bridge_index)).map_err(Error::Network)?;
+
+        let mut pod = pod_netlink(namespaces.handle())?;
+        let pod_index = pod.link_index(POD_LINK).map_err(Error::Network)?;
+        pod.add_address(pod_index, network.ip(), range.prefix_len())
+            .and_then(|_| pod.set_up(pod_index, None))
+            .and_then(|_| pod.add_default_route(range.gateway()))
+            .map_err(Error::Network)?;
+        Ok(network)
+    }
+
+    /// The pod's address on the network.
+    pub fn ip(&self) -> Ipv4Addr {
+        self.lease.ip()
+    }
+
+    /// The host's address on the network, which the bridge has.
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.gateway
+    }
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        // Deleting either end deletes the pair, which would otherwise only
+        // go along with the pod's network namespace.
+        if let Ok(mut host) = Netlink::open() {
+            if let Ok(index) = host.link_index(&self.host_link) {
+                let _ = host.delete_link(index);
+            }
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::slice;
+
+/// The attribute holding the peer of a veth link, from `linux/veth.h`.
+const VETH_INFO_PEER : u16 = 1;
+const ALIGN_TO : usize = 4;
+const RECV_BUFFER : usize = 8192;
+
+#[repr(C)]
+struct IfInfoMsg {
+    family: u8,
+    pad: u8,
+    kind: u16,
+    index: i32,
+    flags: u32,
+    change: u32
+}
+
+#[repr(C)]
+struct IfAddrMsg {
+    family: u8,
+    prefix_len: u8,
+    flags: u8,
+    scope: u8,
+    index: u32
+}
+
+#[repr(C)]
+struct RtMsg {
+    family: u8,
+    dst_len: u8,
+    src_len: u8,
+    tos: u8,
+    table: u8,
+    protocol: u8,
+    scope: u8,
+    kind: u8,
+    flags: u32
+}
+
+/// A socket talking rtnetlink to the network namespace it was made in.
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32
+}
+
+/// A request being put together: its header, the message it carries and
+/// the message's attributes.
+struct Request {
+    buf: Vec<u8>
+}
+
+fn align(len: usize) -> usize {
+    (len + ALIGN_TO - 1) & !(ALIGN_TO - 1)
+}
+
+fn bytes_of<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn link(index: i32) -> IfInfoMsg {
+    IfInfoMsg {
+        family: libc::AF_UNSPEC as u8,
+        pad: 0,
+        kind: 0,
+        index,
+        flags: 0,
+        change: 0
+    }
+}
+
+impl Request {
+    fn new<T>(kind: u16, flags: libc::c_int, message: &T) -> Request {
+        let header = libc::nlmsghdr {
+            nlmsg_len: 0,
+            nlmsg_type: kind,
+            nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_ACK | flags) as u16,
+            nlmsg_seq: 0,
+            nlmsg_pid: 0
+        };
+        let mut request = Request {
+            buf: Vec::new()
+        };
+        request.put(bytes_of(&header));
+        request.put(bytes_of(message));
+        request
+    }
+
+    fn put(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    fn attr(&mut self, kind: u16, data: &[u8]) {
+        self.buf.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.put(data);
+    }
+
+    fn attr_str(&mut self, kind: u16, s: &str) {
+        let mut data = Vec::from(s.as_bytes());
+        data.push(0);
+        self.attr(kind, &data);
+    }
+
+    /// Adds an attribute holding whatever `fill` puts in it.
+    fn nest<F: FnOnce(&mut Request)>(&mut self, kind: u16, fill: F) {
+        let start = self.buf.len();
+        self.attr(kind | libc::NLA_F_NESTED as u16, &[]);
+        fill(self);
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+}
+
+impl Netlink {
+    /// Opens a socket in the calling thread's network namespace.
+    pub fn open() -> io::Result<Netlink> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                         libc::NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Netlink {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0
+        })
+    }
+
+    /// Sends `request` and waits for the kernel to acknowledge it. Returns
+    /// the messages that came before the acknowledgement, past their header.
+    fn send(&mut self, mut request: Request) -> io::Result<Vec<Vec<u8>>> {
+        self.seq += 1;
+        let len = request.buf.len() as u32;
+        request.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        request.buf[8..12].copy_from_slice(&self.seq.to_ne_bytes());
+        let sent = unsafe {
+            libc::send(self.fd.as_raw_fd(), request.buf.as_ptr() as *const libc::c_void,
+                       request.buf.len(), 0)
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let header_len = mem::size_of::<libc::nlmsghdr>();
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; RECV_BUFFER];
+        loop {
+            let received = unsafe {
+                libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void,
+                           buf.len(), 0)
+            };
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let received = received as usize;
+            let mut offset = 0;
+            while offset + header_len <= received {
+                let header : libc::nlmsghdr = unsafe {
+                    ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::nlmsghdr)
+                };
+                let msg_len = header.nlmsg_len as usize;
+                if msg_len < header_len || offset + msg_len > received {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "truncated netlink message"));
+                }
+                let body = &buf[offset + header_len..offset + msg_len];
+                if header.nlmsg_seq == self.seq {
+                    if header.nlmsg_type == libc::NLMSG_ERROR as u16 {
+                        let error = i32::from_ne_bytes([body[0], body[1], body[2], body[3]]);
+                        return match error {
+                            0 => Ok(replies),
+                            e => Err(io::Error::from_raw_os_error(-e))
+                        };
+                    }
+                    replies.push(Vec::from(body));
+                }
+                offset += align(msg_len);
+            }
+        }
+    }
+
+    /// The index of the link called `name`.
+    pub fn link_index(&mut self, name: &str) -> io::Result<i32> {
+        let mut request = Request::new(libc::RTM_GETLINK, 0, &link(0));
+        request.attr_str(libc::IFLA_IFNAME, name);
+        let replies = self.send(request)?;
+        match replies.first() {
+            Some(r) if r.len() >= mem::size_of::<IfInfoMsg>() =>
+                Ok(i32::from_ne_bytes([r[4], r[5], r[6], r[7]])),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("no index for link {}", name)))
+        }
+    }
+
+    pub fn create_bridge(&mut self, name: &str) -> io::Result<()> {
+        let mut request = Request::new(libc::RTM_NEWLINK,
+                                       libc::NLM_F_CREATE | libc::NLM_F_EXCL, &link(0));
+        request.attr_str(libc::IFLA_IFNAME, name);
+        request.nest(libc::IFLA_LINKINFO, |r| r.attr_str(libc::IFLA_INFO_KIND, "bridge"));
+        self.send(request).map(|_| ())
+    }
+
+    /// Creates a veth pair whose `peer` end is in the network namespace of
+    /// the process `peer_pid`.
+    pub fn create_veth(&mut self, name: &str, peer: &str,
+                       peer_pid: libc::pid_t) -> io::Result<()> {
+        let mut request = Request::new(libc::RTM_NEWLINK,
+                                       libc::NLM_F_CREATE | libc::NLM_F_EXCL, &link(0));
+        request.attr_str(libc::IFLA_IFNAME, name);
+        request.nest(libc::IFLA_LINKINFO, |r| {
+            r.attr_str(libc::IFLA_INFO_KIND, "veth");
+            r.nest(libc::IFLA_INFO_DATA, |r| {
+                r.nest(VETH_INFO_PEER, |r| {
+                    r.put(bytes_of(&link(0)));
+                    r.attr_str(libc::IFLA_IFNAME, peer);
+                    r.attr(libc::IFLA_NET_NS_PID, &(peer_pid as u32).to_ne_bytes());
+                });
+            });
+        });
+        self.send(request).map(|_| ())
+    }
+
+    /// Brings the link at `index` up, enslaved to the link at `master` if
+    /// given.
+    pub fn set_up(&mut self, index: i32, master: Option<i32>) -> io::Result<()> {
+        let mut message = link(index);
+        message.flags = libc::IFF_UP as u32;
+        message.change = libc::IFF_UP as u32;
+        let mut request = Request::new(libc::RTM_NEWLINK, 0, &message);
+        if let Some(m) = master {
+            request.attr(libc::IFLA_MASTER, &(m as u32).to_ne_bytes());
+        }
+        self.send(request).map(|_| ())
+    }
+
+    pub fn delete_link(&mut self, index: i32) -> io::Result<()> {
+        self.send(Request::new(libc::RTM_DELLINK, 0, &link(index))).map(|_| ())
+    }
+
+    /// Gives the link at `index` the address `ip` in a network of
+    /// `prefix_len` bits.
+    pub fn add_address(&mut self, index: i32, ip: Ipv4Addr,
+                       prefix_len: u8) -> io::Result<()> {
+        let message = IfAddrMsg {
+            family: libc::AF_INET as u8,
+            prefix_len,
+            flags: 0,
+            scope: libc::RT_SCOPE_UNIVERSE,
+            index: index as u32
+        };
+        let mut request = Request::new(libc::RTM_NEWADDR,
+                                       libc::NLM_F_CREATE | libc::NLM_F_EXCL, &message);
+        request.attr(libc::IFA_LOCAL, &ip.octets());
+        request.attr(libc::IFA_ADDRESS, &ip.octets());
+        self.send(request).map(|_| ())
+    }
+
+    pub fn add_default_route(&mut self, gateway: Ipv4Addr) -> io::Result<()> {
+        let message = RtMsg {
+            family: libc::AF_INET as u8,
+            dst_len: 0,
+            src_len: 0,
+            tos: 0,
+            table: libc::RT_TABLE_MAIN,
+            protocol: libc::RTPROT_BOOT,
+            scope: libc::RT_SCOPE_UNIVERSE,
+            kind: libc::RTN_UNICAST,
+            flags: 0
+        };
+        let mut request = Request::new(libc::RTM_NEWROUTE,
+                                       libc::NLM_F_CREATE | libc::NLM_F_EXCL, &message);
+        request.attr(libc::RTA_GATEWAY, &gateway.octets());
+        self.send(request).map(|_| ())
+    }
+}
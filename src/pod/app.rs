@@ -5,17 +5,22 @@ use super::Volume;
 
 #[derive(Clone, Serialize, Deserialize)]
 struct AppImage {
+    #[serde(default)]
     id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     labels: Option<Vec<NameValue>>
 }
 
 #[allow(non_snake_case)]
 #[derive(Clone, Serialize, Deserialize)]
-struct MountPoint {
-    name: String,
-    path: String,
-    appVolume: Option<Volume>
+pub struct MountPoint {
+    #[serde(rename = "volume")]
+    pub name: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub appVolume: Option<Volume>
 }
 
 #[allow(non_snake_case)]
@@ -23,13 +28,33 @@ struct MountPoint {
 pub struct App {
     name: String,
     image: AppImage,
+    #[serde(skip_serializing_if = "Option::is_none")]
     app: Option<AciJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     readOnlyRootFS: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     mounts: Option<Vec<MountPoint>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     annotations: Option<Vec<NameValue>>
 }
 
 impl App {
+    pub fn new(name: String, image_id: String, app: AciJson,
+               annotations: Vec<NameValue>) -> App {
+        App {
+            name,
+            image: AppImage {
+                id: image_id,
+                name: Some(app.get_name()),
+                labels: None
+            },
+            app: Some(app),
+            readOnlyRootFS: None,
+            mounts: None,
+            annotations: Some(annotations)
+        }
+    }
+
     pub fn get_app(&self) -> Option<AciJson> {
         self.app.clone()
     }
@@ -38,11 +63,39 @@ impl App {
         vec_or_empty(self.annotations.as_ref())
     }
 
+    /// Fills in the image manifest served by the metadata service when the
+    /// pod manifest didn't give one for this app.
+    pub fn set_default_app(&mut self, app: AciJson) {
+        if self.app.is_none() {
+            self.app = Some(app);
+        }
+    }
+
     pub fn get_image_id(&self) -> String {
         self.image.id.clone()
     }
 
+    pub fn set_image_id(&mut self, id: String) {
+        self.image.id = id;
+    }
+
+    pub fn get_image_name(&self) -> Option<String> {
+        self.image.name.clone()
+    }
+
+    pub fn get_image_labels(&self) -> Vec<NameValue> {
+        vec_or_empty(self.image.labels.as_ref())
+    }
+
+    pub fn get_mounts(&self) -> Vec<MountPoint> {
+        vec_or_empty(self.mounts.as_ref())
+    }
+
     pub fn get_name(&self) -> String {
         self.name.clone()
     }
+
+    pub fn read_only_rootfs(&self) -> bool {
+        self.readOnlyRootFS.unwrap_or_default()
+    }
 }
@@ -0,0 +1,48 @@
+use std::fs::{copy, create_dir, write};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+const HOSTS : &str = "hosts";
+const HOSTNAME : &str = "hostname";
+const RESOLV_CONF : &str = "resolv.conf";
+const HOST_RESOLV_CONF : &str = "/etc/resolv.conf";
+
+fn with_path(path: &str, e: Error) -> Error {
+    Error::new(e.kind(), format!("{}: {}", path, e))
+}
+
+/// Writes the `/etc` files every app of a pod sees into `dir`: `hosts`,
+/// where the pod's hostname and apps are all the loopback address,
+/// `hostname`, and `resolv.conf` copied from `resolv_conf` or else the host.
+/// Without either there is no `resolv.conf` and apps keep their image's.
+pub fn write_files(dir: &str, hostname: &str, app_names: &[String],
+                   resolv_conf: Option<&str>) -> Result<(), Error> {
+    create_dir(dir).map_err(|e| with_path(dir, e))?;
+
+    let mut names = vec![String::from("localhost"), String::from(hostname)];
+    for name in app_names {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    let hosts = format!("127.0.0.1\t{}\n::1\tlocalhost ip6-localhost ip6-loopback\n",
+                        names.join(" "));
+    let path = format!("{}{}", dir, HOSTS);
+    write(&path, hosts).map_err(|e| with_path(&path, e))?;
+
+    let path = format!("{}{}", dir, HOSTNAME);
+    write(&path, format!("{}\n", hostname)).map_err(|e| with_path(&path, e))?;
+
+    let source = match resolv_conf {
+        Some(f) => f,
+        None if Path::new(HOST_RESOLV_CONF).exists() => HOST_RESOLV_CONF,
+        None => return Ok(())
+    };
+    if Path::new(source).is_dir() {
+        return Err(with_path(source, Error::new(ErrorKind::InvalidInput,
+                                                "is a directory")));
+    }
+    let path = format!("{}{}", dir, RESOLV_CONF);
+    copy(source, &path).map_err(|e| with_path(source, e))?;
+    Ok(())
+}
@@ -1,81 +1,113 @@
 use crate::aci;
-use aci::Isolator;
+use aci::{Isolator, MountPoint, VolumeMount};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fs::{create_dir_all, set_permissions, Permissions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::path::{Component, Path};
 use uuid::Uuid;
 use crate::util::vec_or_empty;
 use crate::util::NameValue;
 
-mod app;
+pub mod app;
+pub mod etc;
+pub mod status;
 use self::app::App;
 
+const EMPTY_VOLUME_MODE: &str = "0755";
+
 #[allow(non_snake_case)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Volume {
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     readOnly: Option<bool>,
     kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     recursive: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     uid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     gid: Option<String>
 }
 
-#[allow(dead_code, non_snake_case)]
-#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Port {
     name: String,
     hostPort: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
     hostIP: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     podPort: Option<aci::app::Port>
 }
 
+impl Port {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn host_port(&self) -> usize {
+        self.hostPort
+    }
+
+    pub fn host_ip(&self) -> Option<&str> {
+        self.hostIP.as_deref()
+    }
+
+    /// The app port to forward to, when the pod manifest gives it rather
+    /// than naming one of its apps'.
+    pub fn pod_port(&self) -> Option<&aci::app::Port> {
+        self.podPort.as_ref()
+    }
+}
+
 #[allow(dead_code, non_snake_case)]
 #[derive(Serialize, Deserialize)]
 pub struct Pod {
     acVersion: String,
     acKind: String,
+    #[serde(default)]
     uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     apps: Option<Vec<App>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     volumes: Option<Vec<Volume>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     isolators: Option<Vec<Isolator>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     annotations: Option<Vec<NameValue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     ports: Option<Vec<Port>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     userAnnotations: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     userLabels: Option<HashMap<String, String>>
 }
 
 impl Pod {
     pub fn new(uuid: Uuid, version: &str, apps: Option<Vec<App>>,
-               volume_set: HashSet<String>, isolators: Option<Vec<Isolator>>,
-               annotations: Option<Vec<NameValue>>, ports: Option<Vec<Port>>,
-               user_annotations: Option<HashMap<String, String>>,
-               user_labels: Option<HashMap<String, String>>) -> Pod {
+               volume_set: HashSet<String>) -> Pod {
         let mut volumes : Vec<Volume> = Vec::new();
         for volume in volume_set {
-            volumes.push(Volume {
-                name: volume,
-                kind: String::from("empty"),
-                readOnly: Some(false),
-                source: Some(String::new()),
-                recursive: Some(false),
-                mode: None,
-                uid: None,
-                gid: None
-            });
+            volumes.push(Volume::empty(volume));
         }
         Pod {
             acKind: String::from("PodManifest"),
             acVersion: String::from(version),
             uuid: uuid.hyphenated().to_string(),
-            apps: apps,
+            apps,
             volumes: Some(volumes),
-            isolators: isolators,
-            annotations: annotations,
-            ports: ports,
-            userAnnotations: user_annotations,
-            userLabels: user_labels
+            isolators: Some(Vec::new()),
+            annotations: Some(Vec::new()),
+            ports: Some(Vec::new()),
+            userAnnotations: Some(HashMap::new()),
+            userLabels: Some(HashMap::new())
         }
     }
 
@@ -83,11 +115,280 @@ impl Pod {
         vec_or_empty(self.annotations.as_ref())
     }
 
+    /// Sets the annotation `name` to `value`, replacing any it had.
+    pub fn set_annotation(&mut self, name: &str, value: String) {
+        let mut annotations = self.annotations_or_empty();
+        annotations.retain(|a| a.name != name);
+        annotations.push(NameValue {
+            name: String::from(name),
+            value
+        });
+        self.annotations = Some(annotations);
+    }
+
+    pub fn isolators_or_empty(&self) -> Vec<Isolator> {
+        vec_or_empty(self.isolators.as_ref())
+    }
+
+    pub fn ports_or_empty(&self) -> Vec<Port> {
+        vec_or_empty(self.ports.as_ref())
+    }
+
     pub fn apps_or_empty(&self) -> Vec<App> {
         vec_or_empty(self.apps.as_ref())
     }
 
+    pub fn set_apps(&mut self, apps: Vec<App>) {
+        self.apps = Some(apps);
+    }
+
+    pub fn volumes_or_empty(&self) -> Vec<Volume> {
+        vec_or_empty(self.volumes.as_ref())
+    }
+
     pub fn get_uuid(&self) -> String {
         self.uuid.clone()
     }
+
+    pub fn set_uuid(&mut self, uuid: Uuid) {
+        self.uuid = uuid.hyphenated().to_string();
+    }
+}
+
+impl Volume {
+    fn empty(name: String) -> Volume {
+        Volume {
+            name,
+            kind: String::from("empty"),
+            readOnly: None,
+            source: Some(String::new()),
+            recursive: Some(false),
+            mode: None,
+            uid: None,
+            gid: None
+        }
+    }
+
+    /// Whether the volume is mounted read-only where the mount point asks
+    /// for `mount_point_read_only`. The volume's own `readOnly`, if given,
+    /// wins.
+    pub fn read_only(&self, mount_point_read_only: bool) -> bool {
+        match self.readOnly {
+            None => mount_point_read_only,
+            Some(b) => b
+        }
+    }
+
+    /// Returns the host directory backing this volume, creating it under
+    /// `vol_dir` the first time an empty volume is used.
+    fn host_path(&self, vol_dir: &str,
+                 created: &mut HashSet<String>) -> Result<String, Error> {
+        match self.kind.as_str() {
+            "empty" => {
+                let mut path = String::from(vol_dir);
+                path.push_str(&self.name);
+                if !created.contains(&self.name) {
+                    self.create_empty(&path)?;
+                    created.insert(self.name.clone());
+                }
+                Ok(path)
+            }
+            "host" => {
+                match self.source {
+                    Some(ref src) if Path::new(src).is_absolute() =>
+                        Ok(src.clone()),
+                    _ => Err(Error::new(ErrorKind::InvalidInput,
+                                        format!("host volume {} needs an absolute source",
+                                                self.name)))
+                }
+            }
+            kind => Err(Error::new(ErrorKind::InvalidInput,
+                                   format!("volume {} has unknown kind {}",
+                                           self.name, kind)))
+        }
+    }
+
+    fn create_empty(&self, path: &str) -> Result<(), Error> {
+        create_dir_all(path)?;
+
+        let mode_str = match self.mode {
+            Some(ref m) => m.as_str(),
+            None => EMPTY_VOLUME_MODE
+        };
+        let mode = u32::from_str_radix(mode_str, 8).map_err(|_| {
+            Error::new(ErrorKind::InvalidInput,
+                       format!("volume {} has invalid mode {}", self.name, mode_str))
+        })?;
+        set_permissions(path, Permissions::from_mode(mode))?;
+
+        let uid = parse_id(self.uid.as_ref(), &self.name)?;
+        let gid = parse_id(self.gid.as_ref(), &self.name)?;
+        chown(path, Some(uid), Some(gid))
+    }
+}
+
+fn parse_id(id: Option<&String>, volume: &str) -> Result<u32, Error> {
+    match id {
+        None => Ok(0),
+        Some(i) => i.parse::<u32>().map_err(|_| {
+            Error::new(ErrorKind::InvalidInput,
+                       format!("volume {} has invalid owner {}", volume, i))
+        })
+    }
+}
+
+/// Refuses a path to mount a volume at unless it's made of plain names, so
+/// that it can't climb out of the app's rootfs.
+fn checked_mount_path(path: &str) -> Result<String, Error> {
+    if Path::new(path).components()
+        .any(|c| !matches!(c, Component::RootDir | Component::Normal(_))) {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("bad mount path {}", path)));
+    }
+    Ok(String::from(path))
+}
+
+/// Matches an image's mount points (and any extra mounts from the pod
+/// manifest) to the pod's volumes. Mount points without a matching volume
+/// get an implicit empty volume of the same name, as the spec requires.
+pub fn resolve_mounts(volumes: &[Volume], app_mounts: &[app::MountPoint],
+                      mount_points: &[MountPoint], vol_dir: &str,
+                      created: &mut HashSet<String>) -> Result<Vec<VolumeMount>, Error> {
+    let mut resolved = Vec::new();
+
+    for app_mount in app_mounts {
+        let volume = match app_mount.appVolume {
+            Some(ref v) => v.clone(),
+            None => match volumes.iter().find(|v| v.name == app_mount.name) {
+                Some(v) => v.clone(),
+                None => return Err(Error::new(ErrorKind::NotFound,
+                                              format!("no volume named {}",
+                                                      app_mount.name)))
+            }
+        };
+        let image_read_only = mount_points.iter()
+            .any(|mp| mp.path == app_mount.path && mp.read_only());
+        resolved.push(VolumeMount {
+            source: volume.host_path(vol_dir, created)?,
+            path: checked_mount_path(&app_mount.path)?,
+            read_only: volume.read_only(image_read_only)
+        });
+    }
+
+    for mount_point in mount_points {
+        if app_mounts.iter().any(|m| m.path == mount_point.path) {
+            continue;
+        }
+        let volume = match volumes.iter().find(|v| v.name == mount_point.name) {
+            Some(v) => v.clone(),
+            None => Volume::empty(mount_point.name.clone())
+        };
+        resolved.push(VolumeMount {
+            source: volume.host_path(vol_dir, created)?,
+            path: checked_mount_path(&mount_point.path)?,
+            read_only: volume.read_only(mount_point.read_only())
+        });
+    }
+
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::remove_dir_all;
+
+    use crate::util::test_dir;
+
+    fn volumes(json: &str) -> Vec<Volume> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn mount_points(json: &str) -> Vec<MountPoint> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn app_mounts(json: &str) -> Vec<app::MountPoint> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let json = r#"{
+            "acVersion": "0.8.9", "acKind": "PodManifest", "uuid": "",
+            "apps": [{"name": "app", "image": {"id": "sha512-0", "name": "example.com/app"},
+                      "mounts": [{"volume": "data", "path": "/data"}]}],
+            "volumes": [{"name": "data", "kind": "host", "source": "/srv/data"}],
+            "ports": [{"name": "http", "hostPort": 8080}]
+        }"#;
+        let pod : Pod = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_value(&pod).unwrap(),
+                   serde_json::from_str::<serde_json::Value>(json).unwrap());
+    }
+
+    #[test]
+    fn volume_read_only_wins_over_mount_point() {
+        let vs = volumes(r#"[{"name": "a", "kind": "empty"},
+                             {"name": "b", "kind": "empty", "readOnly": true},
+                             {"name": "c", "kind": "empty", "readOnly": false}]"#);
+        assert!(!vs[0].read_only(false));
+        assert!(vs[0].read_only(true));
+        assert!(vs[1].read_only(false));
+        assert!(vs[1].read_only(true));
+        assert!(!vs[2].read_only(false));
+        assert!(!vs[2].read_only(true));
+    }
+
+    #[test]
+    fn mounts_resolve_to_volumes() {
+        let vol_dir = format!("{}/", test_dir("volumes").display());
+        let defined = volumes(r#"[
+            {"name": "data", "kind": "host", "source": "/srv/data"},
+            {"name": "conf", "kind": "host", "source": "/srv/conf", "readOnly": false}]"#);
+        let image = mount_points(r#"[
+            {"name": "data", "path": "/data", "readOnly": true},
+            {"name": "conf", "path": "/etc/app", "readOnly": true},
+            {"name": "cache", "path": "/var/cache"},
+            {"name": "logs", "path": "/var/log", "readOnly": true}]"#);
+        // The pod takes /var/log over with a volume of its own.
+        let pod = app_mounts(r#"[
+            {"volume": "data", "path": "/var/log"},
+            {"volume": "conf", "path": "/extra"}]"#);
+        let mut created = HashSet::new();
+        let mounts = resolve_mounts(&defined, &pod, &image, &vol_dir, &mut created).unwrap();
+
+        let found : Vec<(&str, &str, bool)> = mounts.iter()
+            .map(|m| (m.path.as_str(), m.source.as_str(), m.read_only))
+            .collect();
+        assert_eq!(found, vec![
+            ("/var/log", "/srv/data", true),
+            ("/extra", "/srv/conf", false),
+            ("/data", "/srv/data", true),
+            ("/etc/app", "/srv/conf", false),
+            ("/var/cache", &format!("{}cache", vol_dir)[..], false)
+        ]);
+        assert!(created.contains("cache"));
+        assert!(Path::new(&format!("{}cache", vol_dir)).is_dir());
+        remove_dir_all(&vol_dir).unwrap();
+    }
+
+    #[test]
+    fn mounts_stay_in_the_rootfs() {
+        let defined = volumes(r#"[{"name": "v", "kind": "host", "source": "/srv"}]"#);
+        let mut created = HashSet::new();
+        for path in &["/../../etc", "/a/../../etc", "./etc", "a/./b/.."] {
+            let pod = app_mounts(&format!(r#"[{{"volume": "v", "path": "{}"}}]"#, path));
+            let e = resolve_mounts(&defined, &pod, &[], "/nonexistent/", &mut created)
+                .err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput, "{}", path);
+            let image = mount_points(&format!(r#"[{{"name": "v", "path": "{}"}}]"#, path));
+            assert!(resolve_mounts(&defined, &[], &image, "/nonexistent/", &mut created)
+                    .is_err(), "{}", path);
+        }
+        let pod = app_mounts(r#"[{"volume": "missing", "path": "/x"}]"#);
+        let e = resolve_mounts(&defined, &pod, &[], "/nonexistent/", &mut created)
+            .err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
 }
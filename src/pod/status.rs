@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::{Error, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+use serde_json;
+use uuid::Uuid;
+
+/// How an app finished. Both fields are empty if it never ran.
+#[allow(non_snake_case)]
+#[derive(Serialize)]
+pub struct AppStatus {
+    name: String,
+    exitCode: Option<i32>,
+    signal: Option<i32>
+}
+
+impl AppStatus {
+    pub fn new(name: String, status: Option<ExitStatus>) -> AppStatus {
+        AppStatus {
+            name,
+            exitCode: status.and_then(|s| s.code()),
+            signal: status.and_then(|s| s.signal())
+        }
+    }
+
+    /// The status as a process exit code, using the shell's 128 + signal
+    /// convention for apps that were killed.
+    fn exit_code(&self) -> i32 {
+        match (self.exitCode, self.signal) {
+            (Some(c), _) => c,
+            (None, Some(s)) => 128 + s,
+            (None, None) => 0
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PodStatus {
+    uuid: String,
+    apps: Vec<AppStatus>
+}
+
+impl PodStatus {
+    pub fn new(uuid: Uuid, apps: Vec<AppStatus>) -> PodStatus {
+        PodStatus {
+            uuid: uuid.hyphenated().to_string(),
+            apps
+        }
+    }
+
+    /// The first non-zero app exit code, in the order of the pod's apps.
+    pub fn exit_code(&self) -> i32 {
+        self.apps.iter()
+            .map(|a| a.exit_code())
+            .find(|c| *c != 0)
+            .unwrap_or(0)
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), Error> {
+        let mut f = File::create(path)?;
+        f.write_all(serde_json::to_string(self)?.as_bytes())
+    }
+}
@@ -0,0 +1,247 @@
+//! Forwarding of a pod's ports from the host to its apps.
+//!
+//! A pod with a network namespace of its own can't be reached from the host,
+//! so each of its ports gets a userspace proxy on the host that relays TCP
+//! connections and UDP datagrams to the app listening on the pod's loopback
+//! interface. The sockets on the pod's side are made by a thread that
+//! entered its network namespace, as a socket belongs to the namespace it
+//! was made in.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::error::Error;
+use crate::namespace;
+use crate::pod::Pod;
+
+/// How long a UDP client may stay quiet before its socket in the pod goes.
+const UDP_TIMEOUT : Duration = Duration::from_secs(60);
+const MAX_DATAGRAM : usize = 65536;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Protocol {
+    Tcp,
+    Udp
+}
+
+/// One host port relayed to one port of the pod.
+struct Forward {
+    protocol: Protocol,
+    host: SocketAddr,
+    pod_port: u16
+}
+
+type SocketRequest = (libc::c_int, oneshot::Sender<io::Result<OwnedFd>>);
+
+/// Makes sockets in a pod's network namespace for the proxies.
+struct SocketMaker {
+    requests: mpsc::Sender<SocketRequest>
+}
+
+fn invalid(port: &str, why: String) -> Error {
+    Error::Port(String::from(port), io::Error::new(io::ErrorKind::InvalidInput, why))
+}
+
+/// Works out what each of the pod's ports forwards where: the host port
+/// range starting at `hostPort` to the app port range of the same name, or
+/// to the `podPort` given along with it.
+fn forwards(pod: &Pod) -> Result<Vec<Forward>, Error> {
+    let mut forwards = Vec::new();
+    for port in pod.ports_or_empty() {
+        let app_port = match port.pod_port() {
+            Some(p) => p.clone(),
+            None => pod.apps_or_empty().iter()
+                .filter_map(|a| a.get_app())
+                .flat_map(|a| a.ports())
+                .find(|p| p.name() == port.name())
+                .ok_or_else(|| invalid(port.name(), String::from("no app has this port")))?
+        };
+        let protocol = match app_port.protocol() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            p => return Err(invalid(port.name(), format!("unknown protocol {}", p)))
+        };
+        let ip = match port.host_ip() {
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            Some(ip) => ip.parse()
+                .map_err(|_| invalid(port.name(), format!("bad host IP {}", ip)))?
+        };
+        let count = app_port.count() as usize;
+        if port.host_port() == 0 || port.host_port() + count - 1 > u16::MAX as usize ||
+            app_port.port() as usize + count - 1 > u16::MAX as usize {
+            return Err(invalid(port.name(), String::from("is out of range")));
+        }
+        for i in 0..count {
+            forwards.push(Forward {
+                protocol,
+                host: SocketAddr::new(ip, (port.host_port() + i) as u16),
+                pod_port: app_port.port() + i as u16
+            });
+        }
+    }
+    Ok(forwards)
+}
+
+fn make_socket(kind: libc::c_int) -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(libc::AF_INET, kind | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK, 0)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+impl SocketMaker {
+    /// Starts the thread that makes sockets in the pod's network namespace.
+    /// It ends once the proxies are gone.
+    fn start(namespaces: namespace::Handle) -> Result<SocketMaker, Error> {
+        let (requests, r) = mpsc::channel::<SocketRequest>();
+        let (ready_s, ready_r) = mpsc::channel();
+        thread::spawn(move || {
+            let entered = namespaces.enter_network();
+            let failed = entered.is_err();
+            let _ = ready_s.send(entered);
+            if failed {
+                return;
+            }
+            for (kind, reply) in r {
+                let _ = reply.send(make_socket(kind));
+            }
+        });
+        match ready_r.recv() {
+            Ok(Ok(())) => Ok(SocketMaker {
+                requests
+            }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Error::Namespace(io::Error::other(
+                "could not enter the pod's network")))
+        }
+    }
+
+    async fn make(&self, kind: libc::c_int) -> io::Result<OwnedFd> {
+        let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "socket maker is gone");
+        let (s, r) = oneshot::channel();
+        self.requests.send((kind, s)).map_err(|_| gone())?;
+        r.await.map_err(|_| gone())?
+    }
+}
+
+fn pod_address(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+}
+
+async fn forward_tcp(listener: TcpListener, maker: Arc<SocketMaker>, pod_port: u16) {
+    loop {
+        let (mut client, _addr) = match listener.accept().await {
+            Ok(x) => x,
+            Err(_e) => {
+                continue;
+            }
+        };
+        let maker = maker.clone();
+        tokio::spawn(async move {
+            let socket = match maker.make(libc::SOCK_STREAM).await {
+                Ok(s) => TcpSocket::from_std_stream(s.into()),
+                Err(_e) => return
+            };
+            if let Ok(mut app) = socket.connect(pod_address(pod_port)).await {
+                let _ = copy_bidirectional(&mut client, &mut app).await;
+            }
+        });
+    }
+}
+
+/// Connects a socket in the pod to the app for a new UDP client, and relays
+/// what the app sends back to it until it has been quiet for a while.
+async fn open_udp_session(host: Arc<UdpSocket>, maker: &SocketMaker, pod_port: u16,
+                          client: SocketAddr,
+                          sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>>)
+                          -> io::Result<Arc<UdpSocket>> {
+    let socket = maker.make(libc::SOCK_DGRAM).await?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.connect(pod_address(pod_port)).await?;
+    let socket = Arc::new(socket);
+    sessions.lock().unwrap().insert(client, socket.clone());
+
+    let app = socket.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        while let Ok(Ok(n)) = timeout(UDP_TIMEOUT, app.recv(&mut buf)).await {
+            let _ = host.send_to(&buf[..n], client).await;
+        }
+        sessions.lock().unwrap().remove(&client);
+    });
+    Ok(socket)
+}
+
+async fn forward_udp(host: UdpSocket, maker: Arc<SocketMaker>, pod_port: u16) {
+    let host = Arc::new(host);
+    let sessions = Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (n, client) = match host.recv_from(&mut buf).await {
+            Ok(x) => x,
+            Err(_e) => {
+                continue;
+            }
+        };
+        let session = sessions.lock().unwrap().get(&client).cloned();
+        let session = match session {
+            Some(s) => s,
+            None => match open_udp_session(host.clone(), &maker, pod_port, client,
+                                           sessions.clone()).await {
+                Ok(s) => s,
+                Err(_e) => continue
+            }
+        };
+        let _ = session.send(&buf[..n]).await;
+    }
+}
+
+/// Binds the host side of every port of `pod` and starts relaying them on
+/// `runtime` for as long as it runs. Ports only need forwarding to pods with
+/// a network of their own in `namespaces`; other pods' apps already listen
+/// on the host.
+pub fn start(runtime: &Runtime, pod: &Pod,
+             namespaces: Option<&namespace::Namespaces>) -> Result<(), Error> {
+    let forwards = forwards(pod)?;
+    let namespaces = match namespaces {
+        Some(n) if n.private_network() => n,
+        _ => return Ok(())
+    };
+    if forwards.is_empty() {
+        return Ok(());
+    }
+    let maker = Arc::new(SocketMaker::start(namespaces.handle())?);
+
+    runtime.block_on(async {
+        for forward in forwards {
+            let bind_error = |e| Error::Port(format!("{}", forward.host), e);
+            let maker = maker.clone();
+            match forward.protocol {
+                Protocol::Tcp => {
+                    let listener = TcpListener::bind(forward.host).await.map_err(bind_error)?;
+                    tokio::spawn(forward_tcp(listener, maker, forward.pod_port));
+                }
+                Protocol::Udp => {
+                    let socket = UdpSocket::bind(forward.host).await.map_err(bind_error)?;
+                    tokio::spawn(forward_udp(socket, maker, forward.pod_port));
+                }
+            }
+        }
+        Ok(())
+    })
+}
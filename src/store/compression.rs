@@ -0,0 +1,37 @@
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use xz2::read::XzDecoder;
+
+use std::fs::File;
+use std::io::{Cursor, Error, ErrorKind, Read};
+
+const GZIP_MAGIC : &[u8] = &[0x1f, 0x8b];
+const BZIP2_MAGIC : &[u8] = b"BZh";
+const XZ_MAGIC : &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const TAR_MAGIC : &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET : usize = 257;
+
+/// Wraps an ACI in whichever decoder its first bytes call for. The spec
+/// allows ACIs to be plain tarballs or compressed with gzip, bzip2 or xz.
+pub fn decompress(mut file: File) -> Result<Box<dyn Read>, Error> {
+    let mut head = Vec::new();
+    (&mut file).take((TAR_MAGIC_OFFSET + TAR_MAGIC.len()) as u64)
+        .read_to_end(&mut head)?;
+    let is_tar = head.len() == TAR_MAGIC_OFFSET + TAR_MAGIC.len() &&
+        &head[TAR_MAGIC_OFFSET..] == TAR_MAGIC;
+
+    // Put the sniffed bytes back in front of the rest of the file.
+    let reader = Cursor::new(head.clone()).chain(file);
+    if head.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else if head.starts_with(BZIP2_MAGIC) {
+        Ok(Box::new(BzDecoder::new(reader)))
+    } else if head.starts_with(XZ_MAGIC) {
+        Ok(Box::new(XzDecoder::new(reader)))
+    } else if is_tar {
+        Ok(Box::new(reader))
+    } else {
+        Err(Error::new(ErrorKind::InvalidData,
+                       "ACI is not a tarball, nor compressed with gzip, bzip2 or xz"))
+    }
+}
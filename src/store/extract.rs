@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+
+use tar::{Archive, Entry, EntryType};
+
+const SETID_BITS : u32 = 0o6000;
+/// How many symlinks a link may go through, as with the kernel's limit.
+const MAX_SYMLINKS : usize = 40;
+
+/// What to refuse on top of the checks that are always made.
+#[derive(Clone, Copy, Default)]
+pub struct ExtractOptions {
+    pub refuse_devices: bool,
+    pub refuse_setuid: bool
+}
+
+fn invalid(path: &Path, why: &str) -> Error {
+    Error::new(ErrorKind::InvalidData,
+               format!("ACI entry {} {}", path.display(), why))
+}
+
+/// Returns the entry's path without any leading `./`, as long as it stays
+/// inside the image and is either the manifest or part of the rootfs (or the
+/// top of the image itself, which is empty once cleaned up).
+fn checked_path(path: &Path) -> Result<PathBuf, Error> {
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(c) => clean.push(c),
+            _ => return Err(invalid(path, "escapes the image"))
+        }
+    }
+    if clean.as_os_str().is_empty() {
+        return Ok(clean);
+    }
+    if clean != Path::new("manifest") && !clean.starts_with("rootfs") {
+        return Err(invalid(path, "is neither the manifest nor in the rootfs"));
+    }
+    Ok(clean)
+}
+
+/// Resolves `target` from the directory `dir` (both relative to the image)
+/// for the link at `path`, following the `symlinks` unpacked so far that it
+/// goes through.
+fn resolve(path: &Path, dir: &Path, target: &Path,
+           symlinks: &HashMap<PathBuf, PathBuf>) -> Result<PathBuf, Error> {
+    let mut resolved = dir.to_path_buf();
+    let mut pending : Vec<Component> = target.components().rev().collect();
+    let mut followed = 0;
+    while let Some(component) = pending.pop() {
+        match component {
+            Component::CurDir => {}
+            // Climbing out of rootfs/ would let the link see the manifest
+            // or, further up, the host.
+            Component::ParentDir => {
+                if resolved.components().count() <= 1 {
+                    return Err(invalid(path, "links outside of the rootfs"));
+                }
+                resolved.pop();
+            }
+            Component::Normal(c) => {
+                resolved.push(c);
+                if let Some(next) = symlinks.get(&resolved) {
+                    followed += 1;
+                    if followed > MAX_SYMLINKS {
+                        return Err(invalid(path, "goes through too many symlinks"));
+                    }
+                    resolved.pop();
+                    pending.extend(next.components().rev());
+                }
+            }
+            _ => return Err(invalid(path, "links to an absolute path"))
+        }
+    }
+    Ok(resolved)
+}
+
+/// Checks that a link found at `path` (relative to the image) doesn't point
+/// outside the rootfs, even through other symlinks, and returns what it
+/// points to. Symlinks are relative to the directory they're in, hard links
+/// to the top of the image.
+fn check_link(path: &Path, target: &Path, symlink: bool,
+              symlinks: &HashMap<PathBuf, PathBuf>) -> Result<PathBuf, Error> {
+    let resolved = if symlink {
+        resolve(path, path.parent().unwrap_or(Path::new("")), target, symlinks)?
+    } else {
+        // A hard link is to the file itself, even if that is a symlink.
+        match (target.parent(), target.file_name()) {
+            (Some(dir), Some(name)) => resolve(path, Path::new(""), dir, symlinks)?.join(name),
+            _ => return Err(invalid(path, "links to a directory"))
+        }
+    };
+    if !resolved.starts_with("rootfs") {
+        return Err(invalid(path, "links outside of the rootfs"));
+    }
+    Ok(resolved)
+}
+
+/// Checks an entry against `options` and the `symlinks` unpacked so far.
+/// Returns its path and, if it makes a symlink, where that points.
+fn check_entry<R: Read>(entry: &Entry<R>, options: &ExtractOptions,
+                        symlinks: &HashMap<PathBuf, PathBuf>)
+                        -> Result<(PathBuf, Option<PathBuf>), Error> {
+    let path = checked_path(&entry.path()?)?;
+    let header = entry.header();
+    let entry_type = header.entry_type();
+
+    if path == Path::new("manifest") && !entry_type.is_file() {
+        return Err(invalid(&path, "is not a regular file"));
+    }
+    if (path == Path::new("rootfs") || path.as_os_str().is_empty()) &&
+        !entry_type.is_dir() {
+        return Err(invalid(&path, "is not a directory"));
+    }
+
+    let mut symlink = None;
+    if entry_type.is_symlink() || entry_type.is_hard_link() {
+        let target = match entry.link_name()? {
+            Some(t) => t.into_owned(),
+            None => return Err(invalid(&path, "is a link without a target"))
+        };
+        let linked = check_link(&path, &target, entry_type.is_symlink(), symlinks)?;
+        if entry_type.is_symlink() {
+            symlink = Some(target);
+        } else if let Some(t) = symlinks.get(&linked) {
+            // A hard link to a symlink is a symlink too, but relative to
+            // where the hard link is.
+            check_link(&path, t, true, symlinks)?;
+            symlink = Some(t.clone());
+        }
+    }
+
+    let is_device = entry_type == EntryType::Char || entry_type == EntryType::Block;
+    if options.refuse_devices && is_device {
+        return Err(invalid(&path, "is a device node"));
+    }
+
+    if options.refuse_setuid && header.mode()? & SETID_BITS != 0 {
+        return Err(invalid(&path, "is setuid or setgid"));
+    }
+    Ok((path, symlink))
+}
+
+/// Unpacks an ACI into `dir`, checking each entry before it is written.
+/// Ownership and extended attributes are only kept when running as root,
+/// since nobody else could set them anyway.
+pub fn unpack<R: Read>(archive: &mut Archive<R>, dir: &Path,
+                       options: &ExtractOptions) -> Result<(), Error> {
+    let is_root = unsafe { libc::geteuid() } == 0;
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(is_root);
+    archive.set_unpack_xattrs(is_root);
+
+    // Directories go last, like `Archive::unpack` does, so that a read-only
+    // directory doesn't stop its contents from being unpacked.
+    let mut directories = Vec::new();
+    let mut symlinks = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let (path, symlink) = check_entry(&entry, options, &symlinks)?;
+        // The entry replaces whatever was at its path.
+        symlinks.remove(&path);
+        if let Some(target) = symlink {
+            symlinks.insert(path, target);
+        }
+        if entry.header().entry_type() == EntryType::Directory {
+            directories.push(entry);
+        } else {
+            entry.unpack_in(dir)?;
+        }
+    }
+    // A symlink may lead somewhere else once those it goes through are
+    // unpacked.
+    for (path, target) in &symlinks {
+        check_link(path, target, true, &symlinks)?;
+    }
+    for mut directory in directories {
+        directory.unpack_in(dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::remove_dir_all;
+    use std::io;
+
+    use tar::{Builder, Header};
+
+    use crate::util::test_dir;
+
+    fn links(pairs: &[(&str, &str)]) -> HashMap<PathBuf, PathBuf> {
+        pairs.iter().map(|&(p, t)| (PathBuf::from(p), PathBuf::from(t))).collect()
+    }
+
+    /// Builds an image of `rootfs/` and the given `(path, type, target)`
+    /// entries.
+    fn archive(entries: &[(&str, EntryType, &str)]) -> Archive<io::Cursor<Vec<u8>>> {
+        let mut builder = Builder::new(Vec::new());
+        let mut all = vec![("rootfs", EntryType::Directory, "")];
+        all.extend_from_slice(entries);
+        for &(path, entry_type, target) in &all {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(0o755);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(0);
+            if !target.is_empty() {
+                header.set_link_name(target).unwrap();
+            }
+            builder.append_data(&mut header, path, io::empty()).unwrap();
+        }
+        Archive::new(io::Cursor::new(builder.into_inner().unwrap()))
+    }
+
+    fn unpack_entries(name: &str, entries: &[(&str, EntryType, &str)]) -> Result<(), Error> {
+        let dir = test_dir(&format!("extract-{}", name));
+        let result = unpack(&mut archive(entries), &dir, &ExtractOptions::default());
+        remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn checked_path_cleans_and_confines() {
+        assert_eq!(checked_path(Path::new("./rootfs/./etc")).unwrap(),
+                   Path::new("rootfs/etc"));
+        assert_eq!(checked_path(Path::new("manifest")).unwrap(), Path::new("manifest"));
+        assert_eq!(checked_path(Path::new("./")).unwrap(), Path::new(""));
+        assert!(checked_path(Path::new("rootfs/../../etc")).is_err());
+        assert!(checked_path(Path::new("/rootfs/etc")).is_err());
+        assert!(checked_path(Path::new("other/file")).is_err());
+    }
+
+    #[test]
+    fn check_link_confines_plain_links() {
+        let none = HashMap::new();
+        let link = Path::new("rootfs/usr/bin/sh");
+        assert_eq!(check_link(link, Path::new("../../bin/sh"), true, &none).unwrap(),
+                   Path::new("rootfs/bin/sh"));
+        assert!(check_link(link, Path::new("../../../manifest"), true, &none).is_err());
+        assert!(check_link(link, Path::new("/bin/sh"), true, &none).is_err());
+        assert_eq!(check_link(link, Path::new("rootfs/bin/sh"), false, &none).unwrap(),
+                   Path::new("rootfs/bin/sh"));
+        assert!(check_link(link, Path::new("manifest"), false, &none).is_err());
+    }
+
+    #[test]
+    fn check_link_follows_chains() {
+        let symlinks = links(&[("rootfs/a/b/c/l", "../../..")]);
+        let dev = Path::new("rootfs/dev");
+        assert!(check_link(dev, Path::new("a/b/c/l/a/b/c/l/../../../../../../.."),
+                           true, &symlinks).is_err());
+        assert_eq!(check_link(dev, Path::new("a/b/c/l/etc"), true, &symlinks).unwrap(),
+                   Path::new("rootfs/etc"));
+
+        let looped = links(&[("rootfs/x", "y"), ("rootfs/y", "x")]);
+        assert!(check_link(dev, Path::new("x"), true, &looped).is_err());
+    }
+
+    #[test]
+    fn hard_links_are_to_symlinks_themselves() {
+        let symlinks = links(&[("rootfs/l", "..")]);
+        assert_eq!(check_link(Path::new("rootfs/h"), Path::new("rootfs/l"), false,
+                              &symlinks).unwrap(),
+                   Path::new("rootfs/l"));
+    }
+
+    #[test]
+    fn unpack_refuses_escaping_chains() {
+        assert!(unpack_entries("plain", &[
+            ("rootfs/a/b/c", EntryType::Directory, ""),
+            ("rootfs/a/b/c/l", EntryType::Symlink, "../../.."),
+            ("rootfs/dev", EntryType::Symlink, "a/b/c/l/etc")
+        ]).is_ok());
+        assert!(unpack_entries("chain", &[
+            ("rootfs/a/b/c", EntryType::Directory, ""),
+            ("rootfs/a/b/c/l", EntryType::Symlink, "../../.."),
+            ("rootfs/dev", EntryType::Symlink, "a/b/c/l/a/b/c/l/../../../../../../..")
+        ]).is_err());
+        // The link the chain goes through comes after it.
+        assert!(unpack_entries("later", &[
+            ("rootfs/a/b/c", EntryType::Directory, ""),
+            ("rootfs/a/b/c/x", EntryType::Symlink, "l/../../../.."),
+            ("rootfs/a/b/c/l", EntryType::Symlink, ".")
+        ]).is_err());
+        // A hard link to a symlink points from where the hard link is.
+        assert!(unpack_entries("hard", &[
+            ("rootfs/a/b", EntryType::Directory, ""),
+            ("rootfs/a/b/s", EntryType::Symlink, "../.."),
+            ("rootfs/h", EntryType::Link, "rootfs/a/b/s")
+        ]).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+//! Local content-addressed image store.
+//!
+//! Every ACI is extracted once into `images/<image-id>/` under the store
+//! root, where the image ID is the sha512 of the uncompressed image tarball
+//! as the appc spec defines it. Since finding that ID means decompressing the
+//! whole image, `keys/` remembers which image each ACI file (keyed by the
+//! sha512 of its raw bytes) turned into, so importing the same file again
+//! only costs a hash.
+//!
+//! Images with a `pathWhitelist` are rendered once, together with their
+//! dependencies, into `rendered/<image-id>/` holding only the whitelisted
+//! paths.
+//!
+//! Images are looked up by ID or by name and labels. When a lookup misses,
+//! the ACIs in the store's import directory (if it has one) are imported
+//! and the lookup is tried again.
+
+use sha2::{Digest, Sha512};
+
+use std::cell::Cell;
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, File};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use tar::Archive;
+use uuid::Uuid;
+
+use crate::aci::{AciJson, Dependency};
+use crate::util::NameValue;
+
+mod compression;
+mod extract;
+mod render;
+
+pub use self::extract::ExtractOptions;
+
+pub const DEFAULT_STORE_DIR : &str = "/var/lib/fyc/cas/";
+
+const IMAGES_DIR : &str = "images";
+const KEYS_DIR : &str = "keys";
+const RENDERED_DIR : &str = "rendered";
+const TMP_DIR : &str = "tmp";
+const ID_PREFIX : &str = "sha512-";
+
+pub struct Store {
+    root: PathBuf,
+    import_dir: Option<String>,
+    imported: Cell<bool>,
+    extract_options: ExtractOptions
+}
+
+/// Feeds everything read through it into a sha512 hasher.
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha512
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        s.push_str(&format!("{:02x}", byte));
+    }
+    s
+}
+
+fn file_key(aci_path: &str) -> Result<String, Error> {
+    let mut reader = HashingReader {
+        inner: File::open(aci_path)?,
+        hasher: Sha512::new()
+    };
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(to_hex(&reader.hasher.finalize()))
+}
+
+fn read_manifest(dir: &Path) -> Result<AciJson, Error> {
+    let mut manifest_str = String::new();
+    File::open(dir.join("manifest"))?.read_to_string(&mut manifest_str)?;
+    Ok(serde_json::from_str(&manifest_str)?)
+}
+
+impl Store {
+    pub fn new(root: &str, import_dir: Option<String>,
+               extract_options: ExtractOptions) -> Result<Store, Error> {
+        let store = Store {
+            root: PathBuf::from(root),
+            import_dir,
+            imported: Cell::new(false),
+            extract_options
+        };
+        create_dir_all(store.root.join(IMAGES_DIR))?;
+        create_dir_all(store.root.join(KEYS_DIR))?;
+        create_dir_all(store.root.join(RENDERED_DIR))?;
+        create_dir_all(store.root.join(TMP_DIR))?;
+        Ok(store)
+    }
+
+    /// Adds an ACI to the store if it isn't there already and returns its
+    /// image ID.
+    pub fn import(&self, aci_path: &str) -> Result<String, Error> {
+        let key = file_key(aci_path)?;
+        let key_path = self.root.join(KEYS_DIR).join(&key);
+
+        let mut known_id = String::new();
+        if let Ok(mut f) = File::open(&key_path) {
+            f.read_to_string(&mut known_id)?;
+            // Anything but an image ID, such as a key a crashed fyc left
+            // empty, means importing the ACI again.
+            if known_id.starts_with(ID_PREFIX) && !known_id.contains('/')
+                && self.image_dir(&known_id).is_dir() {
+                return Ok(known_id);
+            }
+        }
+
+        let tmp_dir = self.tmp_dir()?;
+        let id = match self.extract(aci_path, &tmp_dir) {
+            Ok(id) => id,
+            Err(e) => {
+                let _ = remove_dir_all(&tmp_dir);
+                return Err(e);
+            }
+        };
+        self.commit_dir(&tmp_dir, &self.image_dir(&id))?;
+
+        self.commit_key(&id, &key_path)?;
+        Ok(id)
+    }
+
+    // Everything is put together somewhere private first and then renamed
+    // into place, so that nothing half-written is ever visible, even with
+    // several fycs importing the same ACI at once.
+    fn tmp_dir(&self) -> Result<PathBuf, Error> {
+        let tmp_dir = self.root.join(TMP_DIR)
+            .join(Uuid::new_v4().hyphenated().to_string());
+        create_dir_all(&tmp_dir)?;
+        Ok(tmp_dir)
+    }
+
+    fn commit_dir(&self, tmp_dir: &Path, dir: &Path) -> Result<(), Error> {
+        if rename(tmp_dir, dir).is_err() {
+            remove_dir_all(tmp_dir)?;
+            if !dir.is_dir() {
+                return Err(Error::other(format!("could not store {}", dir.display())));
+            }
+        }
+        Ok(())
+    }
+
+    fn commit_key(&self, id: &str, key_path: &Path) -> Result<(), Error> {
+        let tmp_path = self.root.join(TMP_DIR)
+            .join(Uuid::new_v4().hyphenated().to_string());
+        let result = File::create(&tmp_path)
+            .and_then(|mut f| f.write_all(id.as_bytes()))
+            .and_then(|_| rename(&tmp_path, key_path));
+        if result.is_err() {
+            let _ = remove_file(&tmp_path);
+        }
+        result
+    }
+
+    fn extract(&self, aci_path: &str, dir: &Path) -> Result<String, Error> {
+        let decoder = compression::decompress(File::open(aci_path)?)?;
+        let mut archive = Archive::new(HashingReader {
+            inner: decoder,
+            hasher: Sha512::new()
+        });
+        extract::unpack(&mut archive, dir, &self.extract_options)?;
+
+        // The tar reader stops at the end-of-archive marker, but the image ID
+        // covers the whole tarball including any trailing padding.
+        let mut reader = archive.into_inner();
+        io::copy(&mut reader, &mut io::sink())?;
+
+        read_manifest(dir)?;
+
+        let mut id = String::from(ID_PREFIX);
+        id.push_str(&to_hex(&reader.hasher.finalize()));
+        Ok(id)
+    }
+
+    fn image_dir(&self, id: &str) -> PathBuf {
+        self.root.join(IMAGES_DIR).join(id)
+    }
+
+    fn image_ids(&self) -> Result<Vec<String>, Error> {
+        let mut ids = Vec::new();
+        for entry in read_dir(self.root.join(IMAGES_DIR))? {
+            if let Some(id) = entry?.file_name().to_str() {
+                ids.push(String::from(id));
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Imports every ACI in the import directory, once per run. One that
+    /// can't be imported is reported and skipped, so as not to stand in the
+    /// way of finding the others.
+    fn import_all(&self) -> Result<(), Error> {
+        let dir = match self.import_dir {
+            Some(ref d) if !self.imported.get() => d,
+            _ => return Ok(())
+        };
+        self.imported.set(true);
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("aci") {
+                if let Err(e) = self.import(&path.to_string_lossy()) {
+                    eprintln!("fyc: skipping {}: {}", path.display(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds an image by ID. As with other ACEs, a truncated ID is accepted
+    /// as long as it is the prefix of exactly one image in the store.
+    pub fn get_by_id(&self, id: &str) -> Result<String, Error> {
+        if !id.starts_with(ID_PREFIX) {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("{} is not a sha512 image ID", id)));
+        }
+        let mut matches = self.ids_with_prefix(id)?;
+        if matches.is_empty() {
+            self.import_all()?;
+            matches = self.ids_with_prefix(id)?;
+        }
+        match matches.len() {
+            1 => Ok(matches[0].clone()),
+            0 => Err(Error::new(ErrorKind::NotFound,
+                                format!("no image with ID {}", id))),
+            _ => Err(Error::new(ErrorKind::InvalidInput,
+                                format!("image ID {} is ambiguous", id)))
+        }
+    }
+
+    fn ids_with_prefix(&self, id: &str) -> Result<Vec<String>, Error> {
+        Ok(self.image_ids()?.into_iter()
+           .filter(|i| i.starts_with(id))
+           .collect())
+    }
+
+    /// Finds an image whose name matches and which carries every one of the
+    /// given labels.
+    pub fn get_by_name(&self, name: &str,
+                       labels: &[NameValue]) -> Result<String, Error> {
+        if let Some(id) = self.find_by_name(name, labels)? {
+            return Ok(id);
+        }
+        self.import_all()?;
+        match self.find_by_name(name, labels)? {
+            Some(id) => Ok(id),
+            None => Err(Error::new(ErrorKind::NotFound,
+                                   format!("no image named {} with the requested labels",
+                                           name)))
+        }
+    }
+
+    fn find_by_name(&self, name: &str,
+                    labels: &[NameValue]) -> Result<Option<String>, Error> {
+        for id in self.image_ids()? {
+            let manifest = self.manifest(&id)?;
+            if manifest.get_name() != name {
+                continue;
+            }
+            let image_labels = manifest.labels_or_empty();
+            if labels.iter().all(|l| image_labels.iter().any(|il| {
+                il.name == l.name && il.value == l.value
+            })) {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_dependency(&self, dependency: &Dependency) -> Result<String, Error> {
+        let name = dependency.get_image_name();
+        let id = match dependency.get_image_id() {
+            None => return self.get_by_name(&name, &dependency.labels_or_empty()),
+            Some(i) => self.get_by_id(&i)?
+        };
+        if self.manifest(&id)?.get_name() != name {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("image {} is not {}", id, name)));
+        }
+        Ok(id)
+    }
+
+    /// Lists the rootfs directories that make up an image, topmost first:
+    /// the image itself, then each of its dependencies (followed by their
+    /// own dependencies) from the last the manifest lists to the first. As
+    /// the appc spec lays dependencies down in order, a later one's files
+    /// hide an earlier one's, and the image's hide them all.
+    pub fn layers(&self, id: &str) -> Result<Vec<String>, Error> {
+        let mut layers = Vec::new();
+        self.collect_layers(id, &mut Vec::new(), &mut Vec::new(), &mut layers)?;
+        Ok(layers)
+    }
+
+    fn collect_layers(&self, id: &str, path: &mut Vec<String>,
+                      seen: &mut Vec<String>,
+                      layers: &mut Vec<String>) -> Result<(), Error> {
+        if path.iter().any(|p| p == id) {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("image {} depends on itself", id)));
+        }
+        if seen.iter().any(|i| i == id) {
+            return Ok(());
+        }
+        seen.push(String::from(id));
+
+        let manifest = self.manifest(id)?;
+        path.push(String::from(id));
+        let result = match manifest.path_whitelist() {
+            // A whitelist covers the files of the image's dependencies as
+            // well, so the whole lot becomes a single rendered layer.
+            Some(whitelist) => self.render(id, &manifest, &whitelist, path)
+                .map(|rendered| layers.push(rendered)),
+            None => {
+                layers.push(self.rootfs(id));
+                self.collect_dependencies(&manifest, path, seen, layers)
+            }
+        };
+        path.pop();
+        result
+    }
+
+    fn collect_dependencies(&self, manifest: &AciJson, path: &mut Vec<String>,
+                            seen: &mut Vec<String>,
+                            layers: &mut Vec<String>) -> Result<(), Error> {
+        for dependency in manifest.dependencies_or_empty().iter().rev() {
+            let dependency_id = self.get_dependency(dependency)?;
+            self.collect_layers(&dependency_id, path, seen, layers)?;
+        }
+        Ok(())
+    }
+
+    fn render(&self, id: &str, manifest: &AciJson, whitelist: &[String],
+              path: &mut Vec<String>) -> Result<String, Error> {
+        let rendered_dir = self.root.join(RENDERED_DIR).join(id);
+        let mut rendered_rootfs = rendered_dir.to_string_lossy().into_owned();
+        rendered_rootfs.push_str("/rootfs");
+        if rendered_dir.is_dir() {
+            return Ok(rendered_rootfs);
+        }
+
+        let mut layers = vec![self.rootfs(id)];
+        self.collect_dependencies(manifest, path, &mut vec![String::from(id)],
+                                  &mut layers)?;
+
+        let tmp_dir = self.tmp_dir()?;
+        if let Err(e) = render::render_whitelist(&layers, whitelist,
+                                                 &tmp_dir.join("rootfs")) {
+            let _ = remove_dir_all(&tmp_dir);
+            return Err(e);
+        }
+        self.commit_dir(&tmp_dir, &rendered_dir)?;
+        Ok(rendered_rootfs)
+    }
+
+    pub fn manifest(&self, id: &str) -> Result<AciJson, Error> {
+        read_manifest(&self.image_dir(id))
+    }
+
+    /// Path of the shared, extracted rootfs of an image. It must not be
+    /// written to; apps get their own copy-on-write layer on top of it.
+    fn rootfs(&self, id: &str) -> String {
+        let mut rootfs = self.image_dir(id).to_string_lossy().into_owned();
+        rootfs.push_str("/rootfs");
+        rootfs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{read_to_string, write};
+
+    use tar::{Builder, EntryType, Header};
+
+    use crate::util::test_dir;
+
+    /// Writes an uncompressed ACI named `name`, depending on `dependencies`
+    /// and holding `files` as `(path in the rootfs, contents)`.
+    fn write_aci(path: &Path, name: &str, dependencies: &[&str], files: &[(&str, &str)]) {
+        let dependencies : Vec<String> = dependencies.iter()
+            .map(|d| format!(r#"{{"imageName": "{}"}}"#, d))
+            .collect();
+        let manifest = format!(r#"{{"acKind": "ImageManifest", "acVersion": "0.8.9",
+                                   "name": "{}", "dependencies": [{}]}}"#,
+                               name, dependencies.join(","));
+        let mut builder = Builder::new(Vec::new());
+        let mut append = |path: &str, entry_type: EntryType, data: &[u8]| {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(0o755);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        append("manifest", EntryType::Regular, manifest.as_bytes());
+        append("rootfs", EntryType::Directory, &[]);
+        for &(file, contents) in files {
+            append(&format!("rootfs/{}", file), EntryType::Regular, contents.as_bytes());
+        }
+        write(path, builder.into_inner().unwrap()).unwrap();
+    }
+
+    fn store(dir: &Path) -> Store {
+        Store::new(&dir.join("store").to_string_lossy(),
+                   Some(dir.to_string_lossy().into_owned()),
+                   ExtractOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn import_redoes_a_key_left_empty() {
+        let dir = test_dir("store-key");
+        let aci = dir.join("app.aci");
+        write_aci(&aci, "example.com/app", &[], &[("file", "app")]);
+        let store = store(&dir);
+        let id = store.import(&aci.to_string_lossy()).unwrap();
+        assert!(id.starts_with(ID_PREFIX));
+
+        let key_path = store.root.join(KEYS_DIR).join(file_key(&aci.to_string_lossy()).unwrap());
+        write(&key_path, "").unwrap();
+        assert_eq!(store.import(&aci.to_string_lossy()).unwrap(), id);
+        assert_eq!(read_to_string(&key_path).unwrap(), id);
+        assert_eq!(read_dir(store.root.join(TMP_DIR)).unwrap().count(), 0);
+        remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn later_dependencies_go_on_top() {
+        let dir = test_dir("store-layers");
+        write_aci(&dir.join("first.aci"), "example.com/first", &[],
+                  &[("shared", "first"), ("first", "first")]);
+        write_aci(&dir.join("second.aci"), "example.com/second", &[],
+                  &[("shared", "second")]);
+        write_aci(&dir.join("app.aci"), "example.com/app",
+                  &["example.com/first", "example.com/second"], &[("app", "app")]);
+        let store = store(&dir);
+        let id = store.get_by_name("example.com/app", &[]).unwrap();
+        let layers = store.layers(&id).unwrap();
+
+        let names : Vec<String> = layers.iter()
+            .map(|l| store.manifest(Path::new(l).parent().unwrap()
+                                    .file_name().unwrap().to_str().unwrap())
+                 .unwrap().get_name())
+            .collect();
+        assert_eq!(names, vec!["example.com/app", "example.com/second", "example.com/first"]);
+        let shared = layers.iter().map(|l| Path::new(l).join("shared"))
+            .find(|p| p.exists()).unwrap();
+        assert_eq!(read_to_string(shared).unwrap(), "second");
+        remove_dir_all(&dir).unwrap();
+    }
+}
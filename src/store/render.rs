@@ -0,0 +1,166 @@
+use std::ffi::CString;
+use std::fs::{copy, create_dir, read_link, set_permissions, symlink_metadata, Metadata};
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, symlink, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+/// Turns a whitelist entry into a path relative to the rootfs, refusing
+/// anything that would point outside of it.
+fn relative_path(entry: &str) -> Result<PathBuf, Error> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(entry).components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(c) => relative.push(c),
+            _ => return Err(Error::new(ErrorKind::InvalidData,
+                                       format!("bad pathWhitelist entry {}", entry)))
+        }
+    }
+    Ok(relative)
+}
+
+/// The topmost layer that has something at `relative`.
+fn find_in_layers(layers: &[String], relative: &Path) -> Option<(PathBuf, Metadata)> {
+    for layer in layers {
+        let path = Path::new(layer).join(relative);
+        if let Ok(md) = symlink_metadata(&path) {
+            return Some((path, md));
+        }
+    }
+    None
+}
+
+fn copy_entry(src: &Path, md: &Metadata, dst: &Path) -> Result<(), Error> {
+    let file_type = md.file_type();
+    if file_type.is_dir() {
+        create_dir(dst)?;
+    } else if file_type.is_symlink() {
+        symlink(read_link(src)?, dst)?;
+    } else if file_type.is_file() {
+        copy(src, dst)?;
+    } else {
+        // Device nodes, fifos and sockets get recreated rather than read.
+        let c_dst = CString::new(dst.as_os_str().as_bytes()).unwrap();
+        unsafe {
+            if libc::mknod(c_dst.as_ptr(), md.mode(), md.rdev()) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+    }
+
+    lchown(dst, Some(md.uid()), Some(md.gid()))?;
+    if !file_type.is_symlink() {
+        set_permissions(dst, PermissionsExt::from_mode(md.mode()))?;
+    }
+    Ok(())
+}
+
+/// Builds a single rootfs in `target` out of `layers` (topmost first) that
+/// only holds the paths in `whitelist`, plus the directories leading to
+/// them. Each path is taken from the topmost layer that has it.
+pub fn render_whitelist(layers: &[String], whitelist: &[String],
+                        target: &Path) -> Result<(), Error> {
+    create_dir(target)?;
+
+    let mut entries = Vec::new();
+    for entry in whitelist {
+        entries.push(relative_path(entry)?);
+    }
+    entries.sort();
+
+    for relative in entries {
+        let mut partial = PathBuf::new();
+        for component in relative.components() {
+            partial.push(component);
+            let dst = target.join(&partial);
+            if symlink_metadata(&dst).is_ok() {
+                continue;
+            }
+            match find_in_layers(layers, &partial) {
+                Some((src, md)) => {
+                    // Anything leading up to a whitelisted path has to be a
+                    // directory, even if some layer has something else there.
+                    if partial != relative && !md.is_dir() {
+                        return Err(Error::new(ErrorKind::InvalidData,
+                                              format!("{} is not a directory",
+                                                      partial.display())));
+                    }
+                    copy_entry(&src, &md, &dst)?;
+                }
+                None if partial == relative => {}
+                None => break
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, write};
+
+    use crate::util::test_dir;
+
+    /// Makes a layer in `dir` holding `files` as `(path, contents)`.
+    fn layer(dir: &Path, name: &str, files: &[(&str, &str)]) -> String {
+        let root = dir.join(name);
+        for &(path, contents) in files {
+            let path = root.join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(path, contents).unwrap();
+        }
+        create_dir_all(&root).unwrap();
+        root.to_string_lossy().into_owned()
+    }
+
+    fn whitelist(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|e| String::from(*e)).collect()
+    }
+
+    #[test]
+    fn relative_paths_stay_in_the_rootfs() {
+        assert_eq!(relative_path("/usr/./bin/sh").unwrap(), Path::new("usr/bin/sh"));
+        assert_eq!(relative_path("etc").unwrap(), Path::new("etc"));
+        assert!(relative_path("/usr/../../etc").is_err());
+    }
+
+    #[test]
+    fn renders_only_whitelisted_paths_from_the_topmost_layer() {
+        let dir = test_dir("render");
+        let top = layer(&dir, "top", &[("etc/app.conf", "top"), ("bin/app", "app")]);
+        let bottom = layer(&dir, "bottom", &[("etc/app.conf", "bottom"),
+                                             ("etc/passwd", "root"), ("lib/libc", "libc")]);
+        create_dir_all(Path::new(&bottom).join("bin")).unwrap();
+        symlink("app", Path::new(&bottom).join("bin/link")).unwrap();
+        set_permissions(Path::new(&top).join("bin/app"), PermissionsExt::from_mode(0o750))
+            .unwrap();
+        let target = dir.join("rendered");
+        render_whitelist(&[top, bottom],
+                         &whitelist(&["/etc/app.conf", "/etc/passwd", "/bin/app", "/bin/link",
+                                      "/missing/file"]),
+                         &target).unwrap();
+
+        assert_eq!(read_to_string(target.join("etc/app.conf")).unwrap(), "top");
+        assert_eq!(read_to_string(target.join("etc/passwd")).unwrap(), "root");
+        assert_eq!(read_link(target.join("bin/link")).unwrap(), Path::new("app"));
+        assert_eq!(symlink_metadata(target.join("bin/app")).unwrap().mode() & 0o777, 0o750);
+        assert!(!target.join("lib").exists());
+        assert!(!target.join("missing").exists());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_what_leads_through_a_file() {
+        let dir = test_dir("render-file");
+        let top = layer(&dir, "top", &[("etc", "not a directory")]);
+        let bottom = layer(&dir, "bottom", &[("etc/passwd", "root")]);
+        assert!(render_whitelist(&[top.clone(), bottom], &whitelist(&["/etc/passwd"]),
+                                 &dir.join("rendered")).is_err());
+        assert!(render_whitelist(&[top], &whitelist(&["../etc"]),
+                                 &dir.join("rendered-escape")).is_err());
+        remove_dir_all(&dir).unwrap();
+    }
+}
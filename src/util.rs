@@ -1,4 +1,10 @@
 use std::clone::Clone;
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use std::fs::{create_dir_all, remove_dir_all};
+#[cfg(test)]
+use std::path::PathBuf;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct NameValue {
@@ -12,3 +18,12 @@ pub fn vec_or_empty<T: Clone>(v: Option<&Vec<T>>) -> Vec<T> {
         }
         Vec::new()
 }
+
+/// Makes an empty directory of the test `name`'s own, fresh for every run.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("fyc-{}-{}", name, std::process::id()));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}